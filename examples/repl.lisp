@@ -2,7 +2,7 @@
     (let continue true)
     (let result nil)
     (let input nil)
-    (loop continue ((set input (readln)) (println "result := " (if (= (trim input) "quit") (set continue false) (set result (eval input))))))
+    (loop continue ((set input (readln)) (println "result := " (if (= (trim input) "quit") (set continue false) (try (set result (eval input)) (catch e e))))))
 ))
 
 (repl)
@@ -323,14 +323,14 @@
 
 (let launcher_pos (vec2 (/ width 2.0) (height)))
 
-(func init ()
+(func init (display)
       ((set_clear_color display black)
        (set_line_width display 5.0)
        (create_cities)))
 
 
 (create_window width height (lambda (display mouse inputs) (
-    (if first (init))
+    (if first (init display))
     (set first false)
 
     (if (> ground_flash 0)
@@ -346,14 +346,14 @@
     (if (and (< (len bombs) 8) (> (rand) 0.895)) ((set bombs (cons (bomb_create) bombs))))
 
     (set bombs
-         (map bombs
+         (filter (map bombs
               (lambda (bomb)
                 ((let hit (bomb_hit bomb explosions))
                  (if hit (set explosions (cons (explosion_create (nth bomb 2)) explosions)))
                  (if (and (nth bomb 4) (! hit))
                             ((draw_line display (nth bomb 0) (nth bomb 2) (bomb_color bomb))
                              (bomb_tick bomb))
-                          (nil))))))
+                          (nil)))))))
 
     (let is_mouse_down false)
     (map inputs
@@ -368,20 +368,20 @@
     (if (nth plane 0) (draw_line_list display (render_plane plane) white))
 
     (set explosions
-         (map explosions
+         (filter (map explosions
               (lambda (expl) (if (nth expl 3)
                             ((draw_circle display (nth expl 0) (nth expl 1) (nth expl 4))
                              (draw_circle display (nth expl 0) (nth expl 2) dark_grey)
                              (explosion_tick expl))
-                          (nil)))))
+                          (nil))))))
 
     (set missiles
-         (map missiles
+         (filter (map missiles
               (lambda (missile) (if (nth missile 4)
                                ((draw_line display (nth missile 0) (nth missile 2) (missile_color missile))
                                 (missile_tick missile))
                              ((set explosions (cons (explosion_create (nth missile 2)) explosions))
-                              (nil))))))
+                              (nil)))))))
 
     (draw_circle display launcher_pos 40 (vec3 0 0.75 1))
 
@@ -3,4 +3,4 @@
 (let result nil)
 // We use `yield_loop` here in order to pass execution back the the browser after each iteration to allow for event and UI processing
 (yield_loop
- ('(println "result := " (set result (eval (readln))))))
+ ('(println "result := " (try (set result (eval (readln))) (catch e e)))))
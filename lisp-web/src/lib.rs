@@ -4,15 +4,40 @@ use wasm_bindgen::prelude::*;
 
 extern crate lisp;
 pub use lisp::Context;
+use lisp::MemoryLoader;
 
 use std::cell::RefCell;
 
-thread_local!(static CONTEXT: RefCell<Context> = RefCell::new(Context::new()));
+// Keeps a runaway script from hanging or exhausting the page
+const STEP_LIMIT: u64 = 50_000_000;
+const MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+// The page has no filesystem, so modules are handed over by `add_module`
+thread_local!(static LOADER: MemoryLoader = MemoryLoader::new());
+thread_local!(static CONTEXT: RefCell<Context> = RefCell::new(new_context()));
+
+fn new_context() -> Context {
+    let loader = LOADER.with(|loader| loader.clone());
+    let mut ctx = Context::builder().loader(loader).build();
+    ctx.set_step_limit(Some(STEP_LIMIT));
+    ctx.set_memory_limit(Some(MEMORY_LIMIT));
+    ctx
+}
 
 #[wasm_bindgen]
 pub fn eval(source: String) -> String {
     CONTEXT.with(|ctx| {
-        ctx.borrow_mut().eval_module(source).to_string()
+        match ctx.borrow_mut().try_eval_module(source) {
+            Ok(value) => value.to_string(),
+            Err(error) => format!("Exception:\n{}", error),
+        }
+    })
+}
+
+#[wasm_bindgen]
+pub fn add_module(path: String, source: String) {
+    LOADER.with(|loader| {
+        loader.add_module(path, source)
     })
 }
 
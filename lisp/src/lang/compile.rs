@@ -0,0 +1,811 @@
+use super::*;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Constant(u32),
+    LoadLocal(u16, u16),
+    StoreLocal(u16, u16),
+    LoadName(u32),
+    DeclareName(u32),
+    AssignName(u32),
+    MakeFunction(u32),
+    Import,
+    ImportAll,
+    ImportName(u32),
+    Export(u32),
+    Call(u32),
+    TailCall(u32),
+    Return,
+    Eval,
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfCallable(u32, u32),
+    JumpIfSupplied(u32, u32),
+    PushHandler(u32),
+    PopHandler,
+    MatchException(u32, u32),
+    Throw,
+    Quasiquote(u32, u32),
+    MakeMap(u32),
+    Pop,
+}
+
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub name: Option<Ident>,
+    pub code: Vec<Op>,
+    pub locations: Vec<Option<Location>>,
+    pub constants: Vec<Object>,
+    pub names: Vec<Ident>,
+    pub functions: Vec<Wrap<Prototype>>,
+    /// Calls whose callee is itself an expression, like the first expression
+    /// of a block, in the order they were emitted.
+    pub block_calls: Vec<usize>,
+}
+
+impl Chunk {
+    fn new(name: Option<Ident>) -> Self {
+        Chunk {
+            name,
+            code: Vec::new(),
+            locations: Vec::new(),
+            constants: Vec::new(),
+            names: Vec::new(),
+            functions: Vec::new(),
+            block_calls: Vec::new(),
+        }
+    }
+
+    /// The source location of the instruction at `pc`.
+    pub fn location(&self, pc: usize) -> Option<&Location> {
+        self.locations.get(pc).and_then(|l| l.as_ref())
+    }
+
+    /// Whether the call at `pc` reads like a block, its callee being the
+    /// value of the first expression in it.
+    pub fn is_block_call(&self, pc: usize) -> bool {
+        self.block_calls.binary_search(&pc).is_ok()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Prototype {
+    pub params: Vec<usize>,
+    pub required: usize,
+    pub rest: Option<usize>,
+    pub layout: Wrap<Vec<Ident>>,
+    pub chunk: Wrap<Chunk>,
+    pub location: Option<Location>,
+}
+
+enum Access {
+    Local(usize, usize),
+    Name(Ident),
+}
+
+struct FunctionScope {
+    layout: Vec<Ident>,
+    chunk: Chunk,
+}
+
+impl FunctionScope {
+    fn slot(&self, ident: Ident) -> Option<usize> {
+        self.layout.iter().position(|n| *n == ident)
+    }
+}
+
+// Identifiers are resolved against the scopes of the functions being compiled
+// and then against `base`, the live scope the top level code will run in.
+// Anything still unresolved, or all top level code when there is no `base`,
+// falls back to looking up names at runtime.
+pub struct Compiler {
+    base: Option<Scope>,
+    top: Chunk,
+    functions: Vec<FunctionScope>,
+    location: Option<Location>,
+}
+
+impl Compiler {
+    pub fn compile(exp: &Expression, base: Option<Scope>) -> Chunk {
+        if let Some(ref base) = base {
+            let mut declared = Vec::new();
+            declarations(exp, &mut declared);
+            for ident in declared {
+                base.reserve(ident);
+            }
+        }
+
+        let mut compiler = Compiler {
+            base,
+            top: Chunk::new(None),
+            functions: Vec::new(),
+            location: None,
+        };
+
+        compiler.expression(exp);
+        compiler.emit(Op::Return);
+        compiler.top
+    }
+
+    // The top level forms of a module are evaluated in order rather than as a
+    // call to the first one.
+    pub fn compile_module(module: &Expression, base: Option<Scope>) -> Chunk {
+        if let Some(ref base) = base {
+            let mut declared = Vec::new();
+            declarations(module, &mut declared);
+            for ident in declared {
+                base.reserve(ident);
+            }
+        }
+
+        let mut compiler = Compiler {
+            base,
+            top: Chunk::new(None),
+            functions: Vec::new(),
+            location: None,
+        };
+
+        let mut first = true;
+        for token in &module.tokens {
+            if !first {
+                compiler.emit(Op::Pop);
+            }
+            compiler.token(token);
+            first = false;
+        }
+        if first {
+            compiler.constant(Object::Nil);
+        }
+        compiler.emit(Op::Return);
+        compiler.top
+    }
+
+    pub fn compile_token(token: &Token, base: Option<Scope>) -> Chunk {
+        match *token {
+            Token::Expression(ref exp) => Self::compile(exp, base),
+            _ => {
+                let mut compiler = Compiler {
+                    base,
+                    top: Chunk::new(None),
+                    functions: Vec::new(),
+                    location: None,
+                };
+
+                compiler.token(token);
+                compiler.emit(Op::Return);
+                compiler.top
+            }
+        }
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        match self.functions.last_mut() {
+            Some(func) => &mut func.chunk,
+            None => &mut self.top,
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let location = self.location.clone();
+        let chunk = self.chunk();
+        chunk.code.push(op);
+        chunk.locations.push(location);
+        chunk.code.len() - 1
+    }
+
+    // Instructions emitted by `f` are attributed to `exp` in traces, falling
+    // back to the enclosing expression when it has no location.
+    fn located<F: FnOnce(&mut Self)>(&mut self, exp: &Expression, f: F) {
+        let location = match exp.location {
+            Some(ref location) => ::std::mem::replace(&mut self.location, Some(location.clone())),
+            None => self.location.clone(),
+        };
+        f(self);
+        self.location = location;
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk().code.len() as u32;
+        match self.chunk().code[at] {
+            Op::Jump(ref mut to) | Op::JumpIfFalse(ref mut to) |
+            Op::JumpIfCallable(_, ref mut to) | Op::JumpIfSupplied(_, ref mut to) | Op::PushHandler(ref mut to) |
+            Op::MatchException(_, ref mut to) => *to = target,
+            _ => (),
+        }
+    }
+
+    fn block_call(&mut self, callee: &Token, call: usize) {
+        if let Token::Expression(_) = *callee {
+            self.chunk().block_calls.push(call);
+        }
+    }
+
+    fn constant<T: Into<Object>>(&mut self, value: T) {
+        let idx = self.constant_index(value);
+        self.emit(Op::Constant(idx));
+    }
+
+    fn constant_index<T: Into<Object>>(&mut self, value: T) -> u32 {
+        let chunk = self.chunk();
+        chunk.constants.push(value.into());
+        (chunk.constants.len() - 1) as u32
+    }
+
+    fn syntax_error(&mut self, message: &str) {
+        self.constant(Exception::new("syntax", message));
+        self.emit(Op::Throw);
+    }
+
+    fn name(&mut self, ident: Ident) -> u32 {
+        let chunk = self.chunk();
+        let idx = chunk.names.iter().position(|n| *n == ident).unwrap_or_else(|| {
+            chunk.names.push(ident);
+            chunk.names.len() - 1
+        });
+
+        idx as u32
+    }
+
+    fn resolve(&self, ident: Ident) -> Access {
+        for (depth, func) in self.functions.iter().rev().enumerate() {
+            if let Some(slot) = func.slot(ident) {
+                return Access::Local(depth, slot);
+            }
+        }
+
+        if let Some(ref base) = self.base {
+            if let Some((depth, slot)) = base.lookup(ident) {
+                return Access::Local(depth + self.functions.len(), slot);
+            }
+        }
+
+        Access::Name(ident)
+    }
+
+    fn load(&mut self, ident: Ident) {
+        match self.resolve(ident) {
+            Access::Local(depth, slot) => { self.emit(Op::LoadLocal(depth as u16, slot as u16)); },
+            Access::Name(ident) => {
+                let idx = self.name(ident);
+                self.emit(Op::LoadName(idx));
+            },
+        }
+    }
+
+    fn declare(&mut self, ident: Ident) {
+        let slot = match self.functions.last() {
+            Some(func) => func.slot(ident),
+            None => self.base.as_ref().map(|b| b.reserve(ident)),
+        };
+
+        match slot {
+            Some(slot) => { self.emit(Op::StoreLocal(0, slot as u16)); },
+            None => {
+                let idx = self.name(ident);
+                self.emit(Op::DeclareName(idx));
+            },
+        }
+    }
+
+    fn assign(&mut self, ident: Ident) {
+        match self.resolve(ident) {
+            Access::Local(depth, slot) => { self.emit(Op::StoreLocal(depth as u16, slot as u16)); },
+            Access::Name(ident) => {
+                let idx = self.name(ident);
+                self.emit(Op::AssignName(idx));
+            },
+        }
+    }
+
+    fn token(&mut self, token: &Token) {
+        match *token {
+            Token::Ident(ident) => self.load(ident),
+            Token::Literal(ref lit) => self.constant(lit),
+            Token::Keyword(_) => self.constant(Object::Nil),
+            Token::Expression(ref exp) => self.expression(exp),
+        }
+    }
+
+    fn optional_token(&mut self, token: Option<&Token>) {
+        match token {
+            Some(token) => self.token(token),
+            None => self.constant(Object::Nil),
+        }
+    }
+
+    fn expression(&mut self, exp: &Expression) {
+        self.located(exp, |compiler| compiler.call_expression(exp));
+    }
+
+    fn call_expression(&mut self, exp: &Expression) {
+        let mut tokens = exp.tokens.iter();
+        match tokens.next() {
+            Some(&Token::Keyword(ref keyword)) => self.keyword(keyword, tokens.collect()),
+            Some(callee) => {
+                self.token(callee);
+                let mut count = 0;
+                for arg in tokens {
+                    self.token(arg);
+                    count += 1;
+                }
+                let call = self.emit(Op::Call(count));
+                self.block_call(callee, call);
+            },
+            None => self.constant(Object::Nil),
+        }
+    }
+
+    // Compiles a token whose value is returned from the current function. Calls
+    // made here replace the current frame instead of growing the call stack.
+    // `eval` marks values that would have been evaluated once more as the
+    // last argument of a block, which tail calls skip.
+    fn tail_token(&mut self, token: Option<&Token>, eval: bool) {
+        match token {
+            Some(&Token::Expression(ref exp)) => self.tail_expression(exp, eval),
+            token => {
+                self.optional_token(token);
+                if eval {
+                    self.emit(Op::Eval);
+                }
+                self.emit(Op::Return);
+            },
+        }
+    }
+
+    fn tail_expression(&mut self, exp: &Expression, eval: bool) {
+        self.located(exp, |compiler| compiler.tail_call_expression(exp, eval));
+    }
+
+    fn tail_call_expression(&mut self, exp: &Expression, eval: bool) {
+        let mut tokens = exp.tokens.iter();
+        match tokens.next() {
+            Some(&Token::Keyword(Keyword::If)) => {
+                let (cond, yes, no) = (tokens.next(), tokens.next(), tokens.next());
+                self.optional_token(cond);
+                let to_no = self.emit(Op::JumpIfFalse(0));
+                self.tail_token(yes, eval);
+                self.patch(to_no);
+                self.tail_token(no, eval);
+            },
+            Some(&Token::Keyword(ref keyword)) => {
+                self.keyword(keyword, tokens.collect());
+                if eval {
+                    self.emit(Op::Eval);
+                }
+                self.emit(Op::Return);
+            },
+            Some(callee) => {
+                let args: Vec<_> = tokens.collect();
+                self.token(callee);
+                match args.split_last() {
+                    Some((last, rest)) => {
+                        for arg in rest {
+                            self.token(arg);
+                        }
+
+                        // When the callee is a plain value the expression is a
+                        // block that evaluates to its last argument, putting
+                        // that argument in tail position instead.
+                        let to_call = self.emit(Op::JumpIfCallable(rest.len() as u32, 0));
+                        self.tail_token(Some(last), true);
+                        self.patch(to_call);
+                        self.token(last);
+                    },
+                    None => (),
+                }
+                let call = self.emit(Op::TailCall(args.len() as u32));
+                self.block_call(callee, call);
+            },
+            None => {
+                self.constant(Object::Nil);
+                self.emit(Op::Return);
+            },
+        }
+    }
+
+    fn keyword(&mut self, keyword: &Keyword, args: Vec<&Token>) {
+        use self::Keyword::*;
+        let mut args = args.into_iter();
+        match *keyword {
+            If => {
+                let (cond, yes, no) = (args.next(), args.next(), args.next());
+                self.optional_token(cond);
+                let to_no = self.emit(Op::JumpIfFalse(0));
+                self.optional_token(yes);
+                let to_end = self.emit(Op::Jump(0));
+                self.patch(to_no);
+                self.optional_token(no);
+                self.patch(to_end);
+            },
+            Import => {
+                match (args.next(), args.next(), args.next(), args.next()) {
+                    (Some(path), None, None, None) => {
+                        self.token(path);
+                        self.emit(Op::Import);
+                        self.emit(Op::ImportAll);
+                    },
+                    (Some(path), Some(&Token::Keyword(As)), Some(&Token::Ident(alias)), None) => {
+                        self.token(path);
+                        self.emit(Op::Import);
+                        self.declare(alias);
+                    },
+                    (Some(path), Some(&Token::Expression(ref names)), None, None) => {
+                        let idents: Option<Vec<_>> = names.tokens.iter().map(|token| match *token {
+                            Token::Ident(ident) => Some(ident),
+                            _ => None,
+                        }).collect();
+                        let idents = match idents {
+                            Some(idents) => idents,
+                            None => return self.syntax_error("invalid import"),
+                        };
+
+                        self.token(path);
+                        self.emit(Op::Import);
+                        for ident in idents {
+                            let idx = self.name(ident);
+                            self.emit(Op::ImportName(idx));
+                            self.declare(ident);
+                            self.emit(Op::Pop);
+                        }
+                    },
+                    _ => self.syntax_error("invalid import"),
+                }
+            },
+            Export => {
+                for token in args {
+                    match *token {
+                        Token::Ident(ident) => {
+                            let idx = self.name(ident);
+                            self.emit(Op::Export(idx));
+                        },
+                        _ => return self.syntax_error("invalid export"),
+                    }
+                }
+                self.constant(Object::Nil);
+            },
+            As => self.syntax_error("as outside of import"),
+            Func => {
+                match (args.next(), args.next(), args.next()) {
+                    (Some(&Token::Ident(ident)), Some(&Token::Expression(ref params)), Some(&Token::Expression(ref body))) => {
+                        self.function(Some(ident), Some(params), body);
+                        self.declare(ident);
+                    },
+                    (Some(&Token::Ident(ident)), Some(&Token::Expression(ref body)), None) => {
+                        self.function(Some(ident), None, body);
+                        self.declare(ident);
+                    },
+                    _ => self.syntax_error("invalid func"),
+                }
+            },
+            Lambda => {
+                match (args.next(), args.next()) {
+                    (Some(&Token::Expression(ref params)), Some(&Token::Expression(ref body))) => {
+                        self.function(None, Some(params), body);
+                    },
+                    (Some(&Token::Expression(ref body)), None) => {
+                        self.function(None, None, body);
+                    },
+                    _ => self.syntax_error("invalid lamda"),
+                }
+            },
+            Let => {
+                match args.next() {
+                    Some(&Token::Ident(ident)) => {
+                        self.optional_token(args.next());
+                        self.declare(ident);
+                    },
+                    _ => self.syntax_error("invalid let"),
+                }
+            },
+            Set => {
+                match args.next() {
+                    Some(&Token::Ident(ident)) => {
+                        self.optional_token(args.next());
+                        self.assign(ident);
+                    },
+                    _ => self.syntax_error("invalid let"),
+                }
+            },
+            Loop => {
+                match (args.next(), args.next()) {
+                    (Some(cond), Some(body)) => {
+                        let start = self.chunk().code.len() as u32;
+                        self.token(cond);
+                        let to_end = self.emit(Op::JumpIfFalse(0));
+                        self.token(body);
+                        self.emit(Op::Pop);
+                        self.emit(Op::Jump(start));
+                        self.patch(to_end);
+                    },
+                    (Some(body), None) => {
+                        let start = self.chunk().code.len() as u32;
+                        self.token(body);
+                        self.emit(Op::Pop);
+                        self.emit(Op::Jump(start));
+                    },
+                    _ => (),
+                }
+                self.constant(Object::Nil);
+            },
+            Quote => {
+                match args.next() {
+                    Some(token) => self.constant(self::Quote::new(token.clone())),
+                    None => self.constant(Object::Nil),
+                }
+            },
+            Try => self.try_catch(args.collect()),
+            Catch => self.syntax_error("catch outside of try"),
+            Finally => self.syntax_error("finally outside of try"),
+            Defmacro => self.syntax_error("defmacro was not expanded"),
+            Quasiquote => {
+                match args.next() {
+                    Some(template) => self.quasiquote(template),
+                    None => self.constant(Object::Nil),
+                }
+            },
+            Unquote => self.syntax_error("unquote outside of quasiquote"),
+            UnquoteSplicing => self.syntax_error("unquote_splicing outside of quasiquote"),
+            Rest => self.syntax_error("&rest outside of a parameter list"),
+            MapLiteral => {
+                let mut count = 0;
+                for token in args {
+                    self.token(token);
+                    count += 1;
+                }
+                self.emit(Op::MakeMap(count / 2));
+            },
+        }
+    }
+
+    // The unquoted parts of a template are evaluated in order and substituted
+    // into a copy of it when the quasiquote runs.
+    fn quasiquote(&mut self, template: &Token) {
+        let mut unquoted = Vec::new();
+        unquotes(template, &mut unquoted);
+        for token in &unquoted {
+            self.optional_token(*token);
+        }
+
+        let idx = self.constant_index(self::Quote::new(template.clone()));
+        self.emit(Op::Quasiquote(idx, unquoted.len() as u32));
+    }
+
+    fn try_catch(&mut self, args: Vec<&Token>) {
+        let mut args = args.into_iter();
+        let body = args.next();
+        let mut catch = None;
+        let mut finally = None;
+        for arg in args {
+            match *arg {
+                Token::Expression(ref exp) => match exp.tokens.first() {
+                    Some(&Token::Keyword(Keyword::Catch)) if catch.is_none() && finally.is_none() => {
+                        catch = Some(&exp.tokens[1..]);
+                    },
+                    Some(&Token::Keyword(Keyword::Finally)) if finally.is_none() => {
+                        finally = Some(exp.tokens.get(1));
+                    },
+                    _ => return self.syntax_error("invalid try"),
+                },
+                _ => return self.syntax_error("invalid try"),
+            }
+        }
+
+        let catch = match catch.map(|c| (c.get(0), c.get(1), c.get(2))) {
+            Some((Some(&Token::Literal(Literal::String(ref kind))), Some(&Token::Ident(ident)), handler)) => {
+                Some((Some(kind.clone()), ident, handler))
+            },
+            Some((Some(&Token::Ident(ident)), handler, _)) => Some((None, ident, handler)),
+            Some(_) => return self.syntax_error("invalid catch"),
+            None => None,
+        };
+
+        let to_catch = self.emit(Op::PushHandler(0));
+        self.optional_token(body);
+        self.emit(Op::PopHandler);
+        let to_done = self.emit(Op::Jump(0));
+
+        // The exception is on the stack when a handler is entered
+        self.patch(to_catch);
+        let mut to_rethrow = Vec::new();
+        if let Some((kind, ident, handler)) = catch {
+            if let Some(kind) = kind {
+                let kind = self.constant_index(kind);
+                to_rethrow.push(self.emit(Op::MatchException(kind, 0)));
+            }
+            if finally.is_some() {
+                to_rethrow.push(self.emit(Op::PushHandler(0)));
+            }
+            self.declare(ident);
+            self.emit(Op::Pop);
+            self.optional_token(handler);
+            if finally.is_some() {
+                self.emit(Op::PopHandler);
+            }
+            to_rethrow.push(self.emit(Op::Jump(0)));
+            let last = to_rethrow.len() - 1;
+            let to_catch_done = to_rethrow.remove(last);
+
+            for at in to_rethrow {
+                self.patch(at);
+            }
+            if let Some(cleanup) = finally {
+                self.optional_token(cleanup);
+                self.emit(Op::Pop);
+            }
+            self.emit(Op::Throw);
+
+            self.patch(to_catch_done);
+        } else {
+            if let Some(cleanup) = finally {
+                self.optional_token(cleanup);
+                self.emit(Op::Pop);
+            }
+            self.emit(Op::Throw);
+        }
+
+        self.patch(to_done);
+        if let Some(cleanup) = finally {
+            self.optional_token(cleanup);
+            self.emit(Op::Pop);
+        }
+    }
+
+    fn function(&mut self, name: Option<Ident>, params: Option<&Expression>, body: &Expression) {
+        let params = match params.map(parameters) {
+            Some(Ok(params)) => params,
+            Some(Err(message)) => return self.syntax_error(message),
+            None => Parameters::default(),
+        };
+
+        let mut layout = Vec::new();
+        let positional = params.required.iter().cloned().chain(params.optional.iter().map(|o| o.0));
+        for ident in positional.chain(params.rest) {
+            if !layout.contains(&ident) {
+                layout.push(ident);
+            }
+        }
+
+        let mut declared = Vec::new();
+        declarations(body, &mut declared);
+        for ident in declared {
+            if !layout.contains(&ident) {
+                layout.push(ident);
+            }
+        }
+
+        let location = self.location.clone();
+        self.functions.push(FunctionScope {
+            layout,
+            chunk: Chunk::new(name),
+        });
+
+        // Defaults are evaluated in the new scope, after the arguments before
+        // them have been bound.
+        for (idx, &(ident, default)) in params.optional.iter().enumerate() {
+            let supplied = (params.required.len() + idx) as u32;
+            let to_given = self.emit(Op::JumpIfSupplied(supplied, 0));
+            self.optional_token(default);
+            self.declare(ident);
+            self.emit(Op::Pop);
+            self.patch(to_given);
+        }
+        self.tail_expression(body, false);
+        let func = self.functions.pop().expect("function scope");
+
+        let positional = params.required.iter().cloned().chain(params.optional.iter().map(|o| o.0));
+        let proto = Prototype {
+            params: positional.filter_map(|ident| func.slot(ident)).collect(),
+            required: params.required.len(),
+            rest: params.rest.and_then(|ident| func.slot(ident)),
+            layout: wrap(func.layout),
+            chunk: wrap(func.chunk),
+            location,
+        };
+
+        let chunk = self.chunk();
+        chunk.functions.push(wrap(proto));
+        let idx = chunk.functions.len() - 1;
+        self.emit(Op::MakeFunction(idx as u32));
+    }
+}
+
+#[derive(Default)]
+struct Parameters<'a> {
+    required: Vec<Ident>,
+    optional: Vec<(Ident, Option<&'a Token>)>,
+    rest: Option<Ident>,
+}
+
+// Parameter lists are required names, then optional `(name default)` pairs,
+// then `&rest name` to collect any remaining arguments into a list.
+fn parameters(params: &Expression) -> Result<Parameters<'_>, &'static str> {
+    let mut parsed = Parameters::default();
+    let mut tokens = params.tokens.iter();
+    while let Some(token) = tokens.next() {
+        match *token {
+            Token::Ident(ident) if parsed.optional.is_empty() => parsed.required.push(ident),
+            Token::Ident(_) => return Err("required parameter after optional parameter"),
+            Token::Expression(ref exp) => match (exp.tokens.first(), exp.tokens.get(2)) {
+                (Some(&Token::Ident(ident)), None) => parsed.optional.push((ident, exp.tokens.get(1))),
+                _ => return Err("invalid optional parameter"),
+            },
+            Token::Keyword(Keyword::Rest) => match (tokens.next(), tokens.next()) {
+                (Some(&Token::Ident(ident)), None) => parsed.rest = Some(ident),
+                _ => return Err("&rest must be followed by one parameter"),
+            },
+            _ => return Err("invalid parameter"),
+        }
+    }
+
+    Ok(parsed)
+}
+
+// Collects the identifiers a block of code declares in its own scope with `let`
+// or `func`, without descending into nested functions or quoted code.
+fn declarations(exp: &Expression, declared: &mut Vec<Ident>) {
+    let mut tokens = exp.tokens.iter();
+    let rest = match tokens.next() {
+        Some(&Token::Keyword(Keyword::Let)) => {
+            if let Some(&Token::Ident(ident)) = tokens.next() {
+                declared.push(ident);
+            }
+            tokens
+        },
+        Some(&Token::Keyword(Keyword::Func)) => {
+            if let Some(&Token::Ident(ident)) = tokens.next() {
+                declared.push(ident);
+            }
+            return;
+        },
+        Some(&Token::Keyword(Keyword::Import)) => {
+            match (tokens.next(), tokens.next(), tokens.next()) {
+                (_, Some(&Token::Keyword(Keyword::As)), Some(&Token::Ident(alias))) => declared.push(alias),
+                (_, Some(&Token::Expression(ref names)), None) => {
+                    for token in &names.tokens {
+                        if let Token::Ident(ident) = *token {
+                            declared.push(ident);
+                        }
+                    }
+                },
+                _ => (),
+            }
+            return;
+        },
+        Some(&Token::Keyword(Keyword::Catch)) => {
+            match (tokens.next(), tokens.next()) {
+                (Some(&Token::Ident(ident)), _) | (Some(&Token::Literal(_)), Some(&Token::Ident(ident))) => {
+                    declared.push(ident);
+                },
+                _ => (),
+            }
+            exp.tokens.iter()
+        },
+        Some(&Token::Keyword(Keyword::Lambda)) | Some(&Token::Keyword(Keyword::Quote)) |
+        Some(&Token::Keyword(Keyword::Quasiquote)) => return,
+        _ => exp.tokens.iter(),
+    };
+
+    for token in rest {
+        if let Token::Expression(ref exp) = *token {
+            declarations(exp, declared);
+        }
+    }
+}
+
+// Collects the code of each `unquote` and `unquote_splicing` in a quasiquote
+// template, in the order `macros::fill_template` substitutes their values.
+fn unquotes<'a>(token: &'a Token, unquoted: &mut Vec<Option<&'a Token>>) {
+    if let Token::Expression(ref exp) = *token {
+        match exp.tokens.first() {
+            Some(&Token::Keyword(Keyword::Unquote)) | Some(&Token::Keyword(Keyword::UnquoteSplicing)) => {
+                unquoted.push(exp.tokens.get(1));
+            },
+            Some(&Token::Keyword(Keyword::Quasiquote)) => (),
+            _ => {
+                for token in &exp.tokens {
+                    unquotes(token, unquoted);
+                }
+            },
+        }
+    }
+}
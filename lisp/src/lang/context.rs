@@ -1,106 +1,313 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Instant;
 
 use super::*;
+use super::limits::Limits;
 use super::super::modules;
 
 pub struct Context {
     interner: IdentIntern,
-    scopes: Vec<HashMap<Ident, Object>>,
+    prelude: Scope,
+    globals: Scope,
+    scope: Scope,
     native_modules: HashMap<String, (Box<NativeModule>, NativeModuleDescription)>,
     modules: HashMap<String, Expression>,
+    namespaces: HashMap<String, Object>,
+    exports: Option<Vec<Ident>>,
+    loader: Option<Box<dyn ModuleLoader>>,
+    search_paths: Vec<PathBuf>,
+    loading: Vec<String>,
+    console_input: bool,
+    macros: HashMap<Ident, Wrap<Function>>,
+    gensyms: usize,
     yield_point: Option<(Object, Vec<Object>)>,
+    exception: Option<Exception>,
+    running: usize,
+    limits: Limits,
+    diagnostics: Option<Box<dyn FnMut(&str)>>,
 }
 
 impl Context {
     pub fn new() -> Self {
-        let interner = IdentIntern::new();
-        let scopes = vec![HashMap::new()];
+        ContextBuilder::new().build()
+    }
 
-        let mut ctx = Context {
-            interner,
-            scopes,
-            native_modules: HashMap::new(),
-            modules: HashMap::new(),
-            yield_point: None,
-        };
+    /// Starts a context whose access to native modules, module files and the
+    /// console is chosen by the host.
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::new()
+    }
 
-        ctx.import_defaults();
+    // Core is visible from every module without being imported
+    fn import_prelude(&mut self) {
+        if let Object::Map(core) = self.import_module("core") {
+            for (name, value) in core.iter() {
+                if let MapKey::String(ref name) = *name {
+                    let ident = self.interner.get_or_add(name.to_string());
+                    self.prelude.declare(ident, value.clone());
+                }
+            }
+        }
+    }
 
-        ctx
+    pub fn get_scopes(&self) -> Vec<HashMap<Ident, Object>> {
+        let mut scopes = Vec::new();
+        let mut scope = Some(self.scope.clone());
+        while let Some(current) = scope {
+            scopes.push(current.vars());
+            scope = current.parent();
+        }
+        scopes.reverse();
+
+        scopes
     }
 
-    fn import_defaults(&mut self)  {
-        self.register_native_module(modules::Core);
-        self.register_native_module(modules::Math);
-        self.register_native_module(modules::Gfx);
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    pub fn current_scope(&self) -> Scope {
+        self.scope.clone()
+    }
 
-        self.import_module("core");
+    pub fn swap_scope(&mut self, scope: Scope) -> Scope {
+        ::std::mem::replace(&mut self.scope, scope)
     }
 
-    pub fn get_scopes(&self) -> &[HashMap<Ident, Object>] {
-        self.scopes.as_slice()
+    pub fn register_native_module<T: NativeModule + 'static>(&mut self, module: T) {
+        self.register_boxed_module(Box::new(module));
     }
 
-    pub fn register_native_module<T: NativeModule + 'static>(&mut self, mut module: T) {
+    fn register_boxed_module(&mut self, mut module: Box<dyn NativeModule>) {
         let desc = module.register();
-        self.native_modules.insert(desc.name.clone(), (Box::new(module), desc));
+        self.native_modules.insert(desc.name.clone(), (module, desc));
     }
 
-    pub fn import_native_function<T: Into<String>>(&mut self, name: T, ptr: fn(&mut Context, Vec<Object>) -> Object) {
+    pub fn import_native_function<T, F>(&mut self, name: T, func: F)
+    where
+        T: Into<String>,
+        F: Fn(&mut Context, Vec<Object>) -> Object + 'static,
+    {
+        let ident = self.get_or_add_ident(name);
+        self.declare_ident(ident, Object::NativeFunction(NativeFunction::new(func)));
+    }
+
+    /// Like `import_native_function`, but for a plain Rust function whose
+    /// arguments and return value are converted automatically.
+    pub fn import_typed_function<T, F, Args>(&mut self, name: T, func: F)
+    where
+        T: Into<String>,
+        F: IntoNativeFunction<Args>,
+    {
+        let name = name.into();
+        let native = func.into_native_function(name.clone());
         let ident = self.get_or_add_ident(name);
-        self.declare_ident(ident, Object::NativeFunction(NativeFunction{ ptr }));
+        self.declare_ident(ident, Object::NativeFunction(native));
     }
 
+    /// Imports a module, returning its namespace: a map from each name the
+    /// module exports to its value. Modules run once, in a scope of their own,
+    /// and later imports share the same namespace.
     pub fn import_module<T: AsRef<str>>(&mut self, path: T) -> Object {
+        match self.try_import_module(path) {
+            Ok(namespace) => namespace,
+            Err(error) => self.raise_uncaught(error.into_exception()),
+        }
+    }
+
+    /// Like `import_module`, but hands back why the import failed instead of
+    /// reporting it.
+    pub fn try_import_module<T: AsRef<str>>(&mut self, path: T) -> Result<Object, LispError> {
         let path = path.as_ref();
-        if let Some((mut module, desc)) = self.native_modules.remove(path) { //Gross gross gross
-            for func in &desc.funcs {
-                self.import_native_function(func.name.clone(), func.ptr);
-            }
-            let res = module.import(self);
-            self.native_modules.insert(desc.name.clone(), (module, desc));
-            res
-        } else {
-            let mut module: Option<Expression> = None;
+        if let Some(namespace) = self.namespaces.get(path) {
+            return Ok(namespace.clone());
+        }
 
-            if let Some(found_module) = self.modules.get(path) {
-                module = Some(found_module.clone());
-            }
+        if self.native_modules.contains_key(path) {
+            let namespace = self.import_native_module(path);
+            self.namespaces.insert(path.to_string(), namespace.clone());
+            return Ok(namespace);
+        }
 
-            module.unwrap_or_else(|| {
-                use std::fs::File;
-                File::open(path)
-                    .and_then(|f| Ok(self.read_module(path, f)))
-                    .unwrap_or_else(|e| Exception::message(e.to_string()).into())
-            }).eval(self)
+        let name = match self.loader {
+            Some(ref loader) => loader.resolve(path, self.loading.last().map(String::as_str), &self.search_paths)?,
+            None => return Err(Exception::new("permission", format!("importing {} is not allowed", path)).into()),
+        };
+        self.import_loaded_module(name)
+    }
+
+    fn import_native_module(&mut self, name: &str) -> Object {
+        let (mut module, desc) = self.native_modules.remove(name).expect("native module"); //Gross gross gross
+        let namespace: HashMap<_, _> = desc.funcs
+            .iter()
+            .map(|func| (MapKey::String(wrap(func.name.clone())), NativeFunction { func: func.func.clone() }.into()))
+            .collect();
+        module.import(self);
+        self.native_modules.insert(desc.name.clone(), (module, desc));
+
+        namespace.into()
+    }
+
+    fn import_loaded_module(&mut self, name: String) -> Result<Object, LispError> {
+        if let Some(namespace) = self.namespaces.get(&name) {
+            return Ok(namespace.clone());
+        }
+        if self.loading.contains(&name) {
+            let cycle: Vec<_> = self.loading
+                .iter()
+                .skip_while(|n| **n != name)
+                .chain(Some(&name))
+                .cloned()
+                .collect();
+            return Err(Exception::new("import", format!("circular import: {}", cycle.join(" -> "))).into());
         }
+
+        use std::io::Cursor;
+        let source = match self.loader {
+            Some(ref loader) => loader.load(&name)?,
+            None => return Err(Exception::new("permission", format!("importing {} is not allowed", name)).into()),
+        };
+        let module = self.read_module(name.clone(), Cursor::new(source))?;
+
+        let module_scope = self.prelude.child();
+        let caller_scope = self.swap_scope(module_scope);
+        let caller_exports = self.exports.take();
+        self.loading.push(name.clone());
+        let result = self.catch(|ctx| ctx.run_module(module));
+        self.loading.pop();
+        let module_scope = self.swap_scope(caller_scope);
+        let exports = ::std::mem::replace(&mut self.exports, caller_exports);
+        result?;
+
+        let namespace = self.namespace(&module_scope, exports);
+        self.namespaces.insert(name, namespace.clone());
+        Ok(namespace)
+    }
+
+    /// Replaces where imports that do not name a native module are read from.
+    /// Modules already imported keep their namespaces.
+    pub fn set_loader<T: ModuleLoader + 'static>(&mut self, loader: T) {
+        self.loader = Some(Box::new(loader));
+    }
+
+    /// Directories searched for modules that are not found next to the module
+    /// importing them. Starts out as the directories listed in `MASS_LISP_PATH`.
+    pub fn search_paths(&self) -> &[PathBuf] {
+        &self.search_paths
+    }
+
+    pub fn add_search_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.search_paths.push(path.into());
+    }
+
+    fn default_search_paths() -> Vec<PathBuf> {
+        ::std::env::var_os("MASS_LISP_PATH")
+            .map(|paths| ::std::env::split_paths(&paths).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether scripts may read from the console with `read` and `readln`.
+    pub fn allows_console_input(&self) -> bool {
+        self.console_input
+    }
+
+    // Modules without an `export` form export everything they declare
+    fn namespace(&self, scope: &Scope, exports: Option<Vec<Ident>>) -> Object {
+        let vars = scope.vars();
+        let names = exports.unwrap_or_else(|| vars.keys().cloned().collect());
+        let namespace: HashMap<_, _> = names
+            .into_iter()
+            .filter_map(|ident| {
+                let name = self.get_ident_name(ident)?;
+                Some((MapKey::String(wrap(name.to_string())), vars.get(&ident).cloned().unwrap_or(Object::Nil)))
+            })
+            .collect();
+
+        namespace.into()
+    }
+
+    pub fn export(&mut self, ident: Ident) {
+        self.exports.get_or_insert_with(Vec::new).push(ident);
     }
 
     pub fn eval_module<T: AsRef<str>>(&mut self, source: T) -> Object {
+        match self.try_eval_module(source) {
+            Ok(value) => value,
+            Err(error) => self.raise_uncaught(error.into_exception()),
+        }
+    }
+
+    /// Like `eval_module`, but hands back why the module failed to parse or
+    /// run instead of reporting it.
+    pub fn try_eval_module<T: AsRef<str>>(&mut self, source: T) -> Result<Object, LispError> {
         use std::io::Cursor;
         let module = Cursor::new(source.as_ref());
 
         let id = self.modules.len();
-        self.read_module(format!("<anonymous:{}>", id), module).eval(self)
+        let module = self.read_module(format!("<anonymous:{}>", id), module)?;
+        Ok(self.catch(|ctx| ctx.run_module(module))?)
     }
 
-    fn read_module<S: Into<String>, T: ::std::io::Read>(&mut self, name: S, module: T) -> Expression {
+    fn read_module<S: Into<String>, T: ::std::io::Read>(&mut self, name: S, module: T) -> Result<Expression, LispError> {
         let name = name.into();
-        parse::Module::load(&mut self.interner, module)
+        parse::Module::load(&mut self.interner, wrap(name.clone()), module)
             .map(|exp| {
                 self.modules.insert(name.clone(), exp.clone());
                 exp
             })
-            .unwrap_or_else(|err| err.into_exception(name).into())
+            .map_err(|err| err.into_lisp_error(name))
+    }
+
+    fn run_module(&mut self, module: Expression) -> Object {
+        match macros::expand_module(self, &module) {
+            Ok(module) => {
+                let chunk = Compiler::compile_module(&module, Some(self.current_scope()));
+                vm::run(self, wrap(chunk))
+            },
+            Err(exception) => self.raise_uncaught(exception),
+        }
+    }
+
+    // Raises an exception from outside the VM, so it is reported like one
+    // raised by running code.
+    fn raise_uncaught(&mut self, exception: Exception) -> Object {
+        self.enter_vm();
+        self.raise(exception);
+        self.exit_vm();
+        Object::Nil
+    }
+
+    pub fn macroexpand(&mut self, token: &Token) -> Result<Token, Exception> {
+        macros::expand(self, token)
+    }
+
+    /// Macros belong to the context rather than to a module: once defined
+    /// they expand in every module loaded afterwards, and defining one again
+    /// under the same name replaces it.
+    pub fn define_macro(&mut self, name: Ident, func: Wrap<Function>) {
+        self.macros.insert(name, func);
+    }
+
+    pub fn get_macro(&self, name: Ident) -> Option<Wrap<Function>> {
+        self.macros.get(&name).cloned()
+    }
+
+    /// Creates an identifier that no source code can spell, for macros that
+    /// need variables of their own.
+    pub fn gensym(&mut self) -> Ident {
+        self.gensyms += 1;
+        let name = format!("#g{}", self.gensyms);
+        self.interner.get_or_add(name)
     }
 
     pub fn create_scope(&mut self) {
-        self.scopes.push(HashMap::new());
+        self.scope = self.scope.child();
     }
 
     pub fn drop_scope(&mut self) {
-        if self.scopes.len() > 1 {
-            self.scopes.pop();
+        if let Some(parent) = self.scope.parent() {
+            self.scope = parent;
         }
     }
 
@@ -113,34 +320,201 @@ impl Context {
     }
 
     pub fn declare_ident(&mut self, ident: Ident, value: Object) {
-        if let Some(top) = self.scopes.last_mut() {
-            top.insert(ident, value);
-        }
+        self.scope.declare(ident, value);
     }
 
     pub fn assign_ident(&mut self, ident: Ident, value: Object) {
-        for scope in self.scopes.iter_mut().rev() {
-            if let Some(val) = scope.get_mut(&ident) {
-                *val = value;
-                return;
-            }
-        }
+        self.scope.assign(ident, value);
     }
 
-    pub fn resolve_ident(&self, ident: Ident) -> Object {
-        for scope in self.scopes.iter().rev() {
-            if let Some(val) = scope.get(&ident) {
-                return val.clone();
-            }
+    pub fn resolve_ident(&mut self, ident: Ident) -> Object {
+        if let Some(value) = self.scope.resolve(ident) {
+            return value;
         }
 
-        Object::Nil
+        let qualified = self.get_ident_name(ident).and_then(|name| self.resolve_qualified(&self.scope, name));
+        match qualified {
+            Some(Ok(value)) => value,
+            Some(Err(exception)) => self.raise(exception),
+            None => Object::Nil,
+        }
+    }
+
+    // `alias/name` looks up `name` in the namespace imported as `alias`. Plain
+    // names that are not defined are nil, but a qualified name says which
+    // module it expects, so one that is missing is an error.
+    fn resolve_qualified(&self, scope: &Scope, name: &str) -> Option<Result<Object, Exception>> {
+        let split = name.find('/').filter(|&split| split > 0 && split + 1 < name.len())?;
+        let (alias, member) = (&name[..split], &name[split + 1..]);
+        let namespace = self.interner.get(alias).and_then(|alias| scope.resolve(alias));
+        Some(match namespace {
+            Some(Object::Map(namespace)) => namespace
+                .get(&MapKey::String(wrap(member.to_string())))
+                .cloned()
+                .ok_or_else(|| Exception::new("import", format!("module {} does not export {}", alias, member))),
+            _ => Err(Exception::new("undefined", format!("no module is imported as {}", alias))),
+        })
     }
 
     pub fn resolve_literal(&self, lit: &Literal) -> Object {
         lit.into()
     }
 
+    /// Looks up a top-level variable of the main module by name.
+    pub fn get_global<T: AsRef<str>>(&self, name: T) -> Option<Object> {
+        let name = name.as_ref();
+        self.interner
+            .get(name)
+            .and_then(|ident| self.globals.resolve(ident))
+            .or_else(|| self.resolve_qualified(&self.globals, name).and_then(Result::ok))
+    }
+
+    /// Defines or replaces a top-level variable of the main module.
+    pub fn set_global<T: Into<String>, V: Into<Object>>(&mut self, name: T, value: V) {
+        let ident = self.interner.get_or_add(name);
+        self.globals.declare(ident, value.into());
+    }
+
+    /// Calls the global function `name` with `args`, returning the exception
+    /// it raised, if any, rather than reporting it.
+    pub fn call_function<T: AsRef<str>>(&mut self, name: T, args: Vec<Object>) -> Result<Object, LispError> {
+        let name = name.as_ref();
+        match self.get_global(name) {
+            Some(func @ Object::Function(_)) | Some(func @ Object::NativeFunction(_)) => func.try_call(self, args),
+            Some(other) => Err(Exception::new("type", format!("{} is not a function, got {}", name, other.type_name())).into()),
+            None => Err(Exception::new("name", format!("{} is not defined", name)).into()),
+        }
+    }
+
+    /// Runs `f` as a call into the VM, handing back any exception it raised
+    /// instead of reporting it as uncaught.
+    pub fn catch<F: FnOnce(&mut Context) -> Object>(&mut self, f: F) -> Result<Object, Exception> {
+        self.enter_vm();
+        let result = f(self);
+        let exception = self.take_exception();
+        self.exit_vm();
+
+        match exception {
+            Some(exception) => Err(exception),
+            None => Ok(result),
+        }
+    }
+
+    pub fn raise<T: Into<Exception>>(&mut self, exception: T) -> Object {
+        if self.exception.is_none() {
+            self.exception = Some(exception.into());
+        }
+
+        Object::Nil
+    }
+
+    pub fn has_exception(&self) -> bool {
+        self.exception.is_some()
+    }
+
+    pub fn take_exception(&mut self) -> Option<Exception> {
+        self.exception.take()
+    }
+
+    // Exceptions nobody caught are reported once the outermost call into the
+    // VM has unwound.
+    pub fn enter_vm(&mut self) {
+        if self.running == 0 {
+            self.limits.start();
+        }
+        self.running += 1;
+    }
+
+    pub fn exit_vm(&mut self) {
+        self.running -= 1;
+        if self.running == 0 {
+            if let Some(exception) = self.exception.take() {
+                self.report(&format!("Exception:\n{}", exception));
+            }
+            self.limits.finish();
+        }
+    }
+
+    /// Limits each evaluation started from Rust to `limit` VM instructions.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.limits.set_step_limit(limit);
+    }
+
+    /// Interrupts any evaluation still running at `deadline`.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.limits.set_deadline(deadline);
+    }
+
+    /// Limits the bytes held by the strings, lists, maps, native objects and
+    /// quoted code scripts create while the limit is set, along with the
+    /// variables of function calls that closures keep alive. Values are
+    /// counted when a native function returns them, a map literal or
+    /// quasiquote builds them, or a call creates its scope. Lists are charged
+    /// for the nodes they don't share with other lists. Going over raises a
+    /// "memory" exception.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_memory_limit(limit);
+    }
+
+    /// Bytes counted against the memory limit, including values freed since
+    /// the last time the count was brought up to date.
+    pub fn allocated(&self) -> usize {
+        self.limits.allocated()
+    }
+
+    /// Lets native code refuse a large allocation before making it.
+    pub fn check_allocation(&mut self, bytes: usize) -> Result<(), Exception> {
+        self.limits.check_allocation(bytes)
+    }
+
+    pub fn track_allocation(&mut self, obj: &Object) -> Result<(), Exception> {
+        self.limits.track(obj)
+    }
+
+    pub fn track_scope(&mut self, scope: &Scope) -> Result<(), Exception> {
+        self.limits.track_scope(scope)
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.limits.interrupt_handle()
+    }
+
+    /// Whether the running evaluation was stopped by a limit or an interrupt.
+    /// Its "interrupted" exception cannot be caught by `try`.
+    pub fn is_interrupted(&self) -> bool {
+        self.limits.tripped()
+    }
+
+    pub fn step(&mut self) -> Result<(), Exception> {
+        self.limits.step()
+    }
+
+    /// Frees cycles of values nothing refers to, such as closures stored in
+    /// the scope they were defined in, returning how many values were freed.
+    /// Runs on its own as functions are called, so calling this is only needed
+    /// to free memory at a particular point.
+    pub fn collect_garbage(&mut self) -> usize {
+        gc::collect()
+    }
+
+    /// Collector statistics, shared by every context on this thread.
+    pub fn gc_stats(&self) -> GcStats {
+        gc::stats()
+    }
+
+    /// Sends uncaught exceptions and other diagnostics to `sink` instead of
+    /// stderr.
+    pub fn set_diagnostics<F: FnMut(&str) + 'static>(&mut self, sink: F) {
+        self.diagnostics = Some(Box::new(sink));
+    }
+
+    pub fn report(&mut self, message: &str) {
+        match self.diagnostics {
+            Some(ref mut sink) => sink(message),
+            None => eprintln!("{}", message),
+        }
+    }
+
     pub fn do_yield(&mut self, callee: Object, args: Vec<Object>) {
         self.yield_point = Some((callee, args));
     }
@@ -156,11 +530,252 @@ impl Context {
 
     pub fn reset(&mut self) {
         self.interner = IdentIntern::new();
-        self.scopes = vec![HashMap::new()];
-        self.native_modules = HashMap::new();
+        self.prelude = Scope::new();
+        self.globals = self.prelude.child();
+        self.scope = self.globals.clone();
         self.modules = HashMap::new();
+        self.namespaces = HashMap::new();
+        self.exports = None;
+        self.loading = Vec::new();
+        self.macros = HashMap::new();
+        self.gensyms = 0;
         self.yield_point = None;
+        self.exception = None;
+        self.running = 0;
+        self.limits.finish();
+
+        self.import_prelude();
+    }
+}
+
+/// Configures what a `Context` lets scripts reach. By default every native
+/// module is available, modules are loaded from disk and the console can be
+/// read, as with `Context::new`.
+pub struct ContextBuilder {
+    default_modules: bool,
+    native_modules: Vec<Box<dyn NativeModule>>,
+    loader: Option<Box<dyn ModuleLoader>>,
+    console_input: bool,
+}
+
+impl ContextBuilder {
+    pub fn new() -> Self {
+        ContextBuilder {
+            default_modules: true,
+            native_modules: Vec::new(),
+            loader: Some(Box::new(FileSystemLoader::new())),
+            console_input: true,
+        }
+    }
+
+    /// Whether the bundled `math` and `gfx` modules can be imported. `core`
+    /// is always available.
+    pub fn default_modules(mut self, allow: bool) -> Self {
+        self.default_modules = allow;
+        self
+    }
+
+    pub fn native_module<T: NativeModule + 'static>(mut self, module: T) -> Self {
+        self.native_modules.push(Box::new(module));
+        self
+    }
+
+    /// Where imports that do not name a native module are read from.
+    pub fn loader<T: ModuleLoader + 'static>(mut self, loader: T) -> Self {
+        self.loader = Some(Box::new(loader));
+        self
+    }
+
+    /// Makes importing anything but a native module raise a permission
+    /// exception.
+    pub fn deny_imports(mut self) -> Self {
+        self.loader = None;
+        self
+    }
 
-        self.import_defaults();
+    /// Whether `read` and `readln` may read the console. When denied they
+    /// raise a permission exception.
+    pub fn console_input(mut self, allow: bool) -> Self {
+        self.console_input = allow;
+        self
+    }
+
+    pub fn build(self) -> Context {
+        let prelude = Scope::new();
+        let globals = prelude.child();
+
+        let mut ctx = Context {
+            interner: IdentIntern::new(),
+            scope: globals.clone(),
+            globals,
+            prelude,
+            native_modules: HashMap::new(),
+            modules: HashMap::new(),
+            namespaces: HashMap::new(),
+            exports: None,
+            loader: self.loader,
+            search_paths: Context::default_search_paths(),
+            loading: Vec::new(),
+            console_input: self.console_input,
+            macros: HashMap::new(),
+            gensyms: 0,
+            yield_point: None,
+            exception: None,
+            running: 0,
+            limits: Limits::new(),
+            diagnostics: None,
+        };
+
+        ctx.register_native_module(modules::Core);
+        if self.default_modules {
+            ctx.register_native_module(modules::Math);
+            ctx.register_native_module(modules::Gfx);
+        }
+        for module in self.native_modules {
+            ctx.register_boxed_module(module);
+        }
+        ctx.import_prelude();
+
+        ctx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modules(files: &[(&str, &str)]) -> MemoryLoader {
+        files
+            .iter()
+            .fold(MemoryLoader::new(), |loader, &(path, source)| loader.with_module(path, source))
+    }
+
+    // A module is a block, so each source starts with a value rather than a
+    // function
+    fn eval_in(ctx: &mut Context, source: &str) -> String {
+        ctx.eval_module(format!("(let result nil) {}", source)).to_string()
+    }
+
+    fn eval(files: &[(&str, &str)], source: &str) -> String {
+        eval_in(&mut Context::builder().loader(modules(files)).build(), source)
+    }
+
+    const UTIL: &str = "(let hidden 1) (func helper () (+ hidden 0)) (let shared 2) (export helper shared)";
+
+    #[test]
+    fn modules_export_only_what_they_list() {
+        let files = &[("util.lisp", UTIL), ("open.lisp", "(let a 1) (let b 2)")];
+        assert_eq!(eval(files, "(import \"util.lisp\") (list (helper) shared hidden)"), "List(1 2 nil)");
+        assert_eq!(eval(files, "(import \"open.lisp\") (list a b)"), "List(1 2)");
+    }
+
+    #[test]
+    fn aliases_give_qualified_names() {
+        let files = &[("util.lisp", UTIL)];
+        assert_eq!(eval(files, "(import \"util.lisp\" as u) (list (u/helper) u/shared helper)"), "List(1 2 nil)");
+        assert_eq!(
+            eval(files, "(import \"util.lisp\" as u) (try u/hidden (catch e (list (exception_type e) (exception_message e))))"),
+            "List(import module u does not export hidden)"
+        );
+        assert_eq!(eval(files, "(try v/shared (catch e (list (exception_type e) (exception_message e))))"), "List(undefined no module is imported as v)");
+    }
+
+    #[test]
+    fn selective_imports_bind_only_the_names_given() {
+        let files = &[("util.lisp", UTIL)];
+        assert_eq!(eval(files, "(import \"util.lisp\" (shared)) (list shared helper)"), "List(2 nil)");
+        assert_eq!(
+            eval(files, "(try (import \"util.lisp\" (hidden)) (catch e (exception_message e)))"),
+            "module does not export hidden"
+        );
+    }
+
+    #[test]
+    fn modules_run_once_per_context() {
+        let files = &[("util.lisp", UTIL)];
+        assert_eq!(eval(files, "(import \"util.lisp\" as a) (import \"util.lisp\" as b) (identical a b)"), "true");
+    }
+
+    #[test]
+    fn imports_resolve_next_to_the_importing_module() {
+        let files = &[
+            ("main.lisp", "(import \"lib/inner.lisp\" as inner) (let value inner/value)"),
+            ("lib/inner.lisp", "(import \"leaf.lisp\" as leaf) (let value (+ leaf/value 1))"),
+            ("lib/leaf.lisp", "(let value 1)"),
+        ];
+        assert_eq!(eval(files, "(import \"main.lisp\" as m) m/value"), "2");
+    }
+
+    #[test]
+    fn search_paths_are_tried_after_the_importer() {
+        let mut ctx = Context::builder().loader(modules(&[("lib/found.lisp", "(let value 7)")])).build();
+        ctx.add_search_path("lib");
+        assert_eq!(eval_in(&mut ctx, "(import \"found.lisp\" as f) f/value"), "7");
+        assert_eq!(
+            eval_in(&mut ctx, "(try (import \"missing.lisp\") (catch e (exception_message e)))"),
+            "module not found: missing.lisp"
+        );
+    }
+
+    #[test]
+    fn modules_are_cached_by_canonical_path() {
+        let files = &[("util.lisp", UTIL)];
+        assert_eq!(eval(files, "(import \"util.lisp\" as a) (import \"sub/../util.lisp\" as b) (identical a b)"), "true");
+    }
+
+    #[test]
+    fn circular_imports_are_reported() {
+        let files = &[("a.lisp", "(let x 1) (import \"b.lisp\")"), ("b.lisp", "(let y 1) (import \"a.lisp\")")];
+        assert_eq!(
+            eval(files, "(try (import \"a.lisp\") (catch e (exception_message e)))"),
+            "circular import: a.lisp -> b.lisp -> a.lisp"
+        );
+    }
+
+    #[test]
+    fn traces_name_modules_by_canonical_path() {
+        let files = &[("fail.lisp", "(let x 1) (func fail () (throw \"bad\"))")];
+        let trace = eval(files, "(import \"./sub/../fail.lisp\" as f) (try (f/fail) (catch e (exception_trace e)))");
+        assert!(trace.contains("fail (fail.lisp:1:"), "{}", trace);
+    }
+
+    struct Greeter;
+
+    impl NativeModule for Greeter {
+        fn register(&mut self) -> NativeModuleDescription {
+            let mut desc = NativeModuleDescription::new("greeter");
+            desc.register_typed_function("greet", |name: String| format!("hello {}", name));
+            desc
+        }
+    }
+
+    #[test]
+    fn sandboxes_choose_the_native_modules() {
+        let mut ctx = Context::builder().default_modules(false).native_module(Greeter).build();
+        assert_eq!(
+            eval_in(&mut ctx, "(try (import \"math\") (catch e (exception_message e)))"),
+            "module not found: math"
+        );
+        assert_eq!(eval_in(&mut ctx, "(import \"greeter\" as g) (g/greet \"you\")"), "hello you");
+        assert_eq!(eval_in(&mut ctx, "(list (len (list 1 2)))"), "List(2)");
+    }
+
+    #[test]
+    fn sandboxes_can_deny_file_imports() {
+        let mut ctx = Context::builder().deny_imports().build();
+        assert_eq!(
+            eval_in(&mut ctx, "(try (import \"util.lisp\") (catch e (list (exception_type e) (exception_message e))))"),
+            "List(permission importing util.lisp is not allowed)"
+        );
+        assert_eq!(eval_in(&mut ctx, "(import \"math\" as m) (m/sqrt 9)"), "3");
+    }
+
+    #[test]
+    fn sandboxes_can_deny_console_input() {
+        let mut ctx = Context::builder().console_input(false).build();
+        for call in &["(read)", "(readln)"] {
+            let source = format!("(try {} (catch e (list (exception_type e) (exception_message e))))", call);
+            assert_eq!(eval_in(&mut ctx, &source), "List(permission console input is not allowed)");
+        }
     }
 }
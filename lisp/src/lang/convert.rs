@@ -0,0 +1,419 @@
+use std::any::Any;
+
+use super::*;
+
+/// A Rust value that can be taken from a lisp argument.
+pub trait FromObject: Sized {
+    /// Converts `obj`, or returns `None` when it is the wrong type.
+    fn from_object(obj: &Object) -> Option<Self>;
+
+    /// Describes the accepted values for error messages, e.g. "number".
+    fn expected() -> String;
+
+    /// Whether the argument may be left out, in which case it reads as nil.
+    fn optional() -> bool {
+        false
+    }
+}
+
+/// A Rust value that can be handed back to lisp.
+pub trait IntoObject {
+    fn into_object(self) -> Object;
+}
+
+/// The return value of a typed native function: either a plain value or a
+/// `Result` whose error is raised as an exception.
+pub trait NativeReturn {
+    fn into_result(self) -> Result<Object, Exception>;
+}
+
+impl<T: IntoObject> NativeReturn for T {
+    fn into_result(self) -> Result<Object, Exception> {
+        Ok(self.into_object())
+    }
+}
+
+impl<T: IntoObject> NativeReturn for Result<T, Exception> {
+    fn into_result(self) -> Result<Object, Exception> {
+        self.map(IntoObject::into_object)
+    }
+}
+
+/// Wraps a Rust value stored in a native object.
+#[derive(Debug, Clone)]
+pub struct Native<T>(pub T);
+
+impl FromObject for Object {
+    fn from_object(obj: &Object) -> Option<Self> {
+        Some(obj.clone())
+    }
+
+    fn expected() -> String {
+        "any value".to_string()
+    }
+}
+
+impl FromObject for bool {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::Boolean(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        "boolean".to_string()
+    }
+}
+
+impl FromObject for Number {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::Number(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        "number".to_string()
+    }
+}
+
+impl FromObject for i64 {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::Number(Number::Integer(val)) => Some(val),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        "integer".to_string()
+    }
+}
+
+impl FromObject for f64 {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::Number(val) => Some(val.as_float()),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        "number".to_string()
+    }
+}
+
+impl FromObject for String {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::String(ref val) => Some((**val).clone()),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        "string".to_string()
+    }
+}
+
+impl<T: FromObject> FromObject for Vec<T> {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::List(ref items) => items.iter().map(T::from_object).collect(),
+            Object::Nil => Some(Vec::new()),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        format!("list of {}", T::expected())
+    }
+}
+
+impl<T: FromObject> FromObject for Option<T> {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::Nil => Some(None),
+            _ => T::from_object(obj).map(Some),
+        }
+    }
+
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+
+    fn optional() -> bool {
+        true
+    }
+}
+
+impl<T: Any + Clone> FromObject for Native<T> {
+    fn from_object(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::NativeObject(ref val) => val.downcast_ref::<T>().map(|val| Native(val.clone())),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        format!("nativeobject {}", ::std::any::type_name::<T>())
+    }
+}
+
+impl IntoObject for Object {
+    fn into_object(self) -> Object {
+        self
+    }
+}
+
+impl IntoObject for () {
+    fn into_object(self) -> Object {
+        Object::Nil
+    }
+}
+
+impl IntoObject for bool {
+    fn into_object(self) -> Object {
+        self.into()
+    }
+}
+
+impl IntoObject for Number {
+    fn into_object(self) -> Object {
+        self.into()
+    }
+}
+
+impl IntoObject for i64 {
+    fn into_object(self) -> Object {
+        self.into()
+    }
+}
+
+impl IntoObject for f64 {
+    fn into_object(self) -> Object {
+        self.into()
+    }
+}
+
+impl IntoObject for String {
+    fn into_object(self) -> Object {
+        self.into()
+    }
+}
+
+impl<'a> IntoObject for &'a str {
+    fn into_object(self) -> Object {
+        self.into()
+    }
+}
+
+impl<T: IntoObject> IntoObject for Vec<T> {
+    fn into_object(self) -> Object {
+        Object::List(wrap(self.into_iter().map(IntoObject::into_object).collect()))
+    }
+}
+
+impl<T: IntoObject> IntoObject for Option<T> {
+    fn into_object(self) -> Object {
+        match self {
+            Some(val) => val.into_object(),
+            None => Object::Nil,
+        }
+    }
+}
+
+impl<T: Any + Clone> IntoObject for Native<T> {
+    fn into_object(self) -> Object {
+        Object::create_native_object(self.0)
+    }
+}
+
+// Tuples travel as fixed-length lists.
+macro_rules! impl_tuple {
+    ($len:expr; $($name:ident),+) => {
+        #[allow(non_snake_case)]
+        impl<$($name: FromObject),+> FromObject for ($($name,)+) {
+            fn from_object(obj: &Object) -> Option<Self> {
+                let items = match *obj {
+                    Object::List(ref items) if items.len() == $len => items,
+                    _ => return None,
+                };
+                let mut items = items.iter();
+                $( let $name = $name::from_object(items.next()?)?; )+
+                Some(($($name,)+))
+            }
+
+            fn expected() -> String {
+                let items: Vec<String> = vec![$($name::expected()),+];
+                format!("list of ({})", items.join(" "))
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$($name: IntoObject),+> IntoObject for ($($name,)+) {
+            fn into_object(self) -> Object {
+                let ($($name,)+) = self;
+                Object::List(wrap(vec![$($name.into_object()),+].into()))
+            }
+        }
+    };
+}
+
+impl_tuple!(1; A);
+impl_tuple!(2; A, B);
+impl_tuple!(3; A, B, C);
+impl_tuple!(4; A, B, C, D);
+
+/// Converts argument `position` (counting from 1) of the native function
+/// `name`, for functions that take their arguments untyped.
+pub fn from_argument<T: FromObject>(name: &str, position: usize, arg: &Object) -> Result<T, Exception> {
+    T::from_object(arg).ok_or_else(|| Exception::new("type", format!(
+        "{} expects argument {} to be {}, got {}",
+        name, position, T::expected(), arg.type_name()
+    )))
+}
+
+/// A plain Rust function that can be called from lisp. `Args` is the tuple of
+/// its parameter types and only exists to tell the impls apart.
+pub trait IntoNativeFunction<Args> {
+    fn into_native_function(self, name: String) -> NativeFunction;
+}
+
+macro_rules! impl_into_native_function {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case, unused_mut, unused_variables)]
+        impl<Func, Ret, $($name),*> IntoNativeFunction<($($name,)*)> for Func
+        where
+            Func: Fn($($name),*) -> Ret + 'static,
+            Ret: NativeReturn,
+            $($name: FromObject),*
+        {
+            fn into_native_function(self, name: String) -> NativeFunction {
+                NativeFunction::new(move |ctx, args| {
+                    let optional: &[bool] = &[$($name::optional()),*];
+                    let required = optional.iter().rposition(|opt| !opt).map_or(0, |pos| pos + 1);
+                    if args.len() < required || args.len() > optional.len() {
+                        return ctx.raise(vm::arity_exception(&name, required, Some(optional.len()), args.len()));
+                    }
+
+                    let mut args = args.into_iter().chain(::std::iter::repeat(Object::Nil));
+                    let mut position = 0;
+                    $(
+                        position += 1;
+                        let arg = args.next().unwrap_or(Object::Nil);
+                        let $name = match from_argument::<$name>(&name, position, &arg) {
+                            Ok(val) => val,
+                            Err(exception) => return ctx.raise(exception),
+                        };
+                    )*
+
+                    match self($($name),*).into_result() {
+                        Ok(val) => val,
+                        Err(exception) => ctx.raise(exception),
+                    }
+                })
+            }
+        }
+    };
+}
+
+impl_into_native_function!();
+impl_into_native_function!(A);
+impl_into_native_function!(A, B);
+impl_into_native_function!(A, B, C);
+impl_into_native_function!(A, B, C, D);
+impl_into_native_function!(A, B, C, D, E);
+impl_into_native_function!(A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: FromObject + IntoObject + Clone + PartialEq + ::std::fmt::Debug>(val: T) {
+        assert_eq!(T::from_object(&val.clone().into_object()), Some(val));
+    }
+
+    // A module is a block, so each source starts with a value rather than a
+    // function
+    fn eval(ctx: &mut Context, source: &str) -> String {
+        ctx.eval_module(format!("(let result nil) {}", source)).to_string()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        round_trip(true);
+        round_trip(42i64);
+        round_trip(1.5f64);
+        round_trip("text".to_string());
+        round_trip(vec![1i64, 2, 3]);
+        round_trip(Some(3i64));
+        round_trip(None::<i64>);
+        round_trip((1i64, "a".to_string()));
+        round_trip((1i64, 2.5f64, true, vec!["b".to_string()]));
+
+        let native = Native(vec![1u8, 2]).into_object();
+        assert_eq!(Native::<Vec<u8>>::from_object(&native).map(|n| n.0), Some(vec![1, 2]));
+        assert!(Native::<String>::from_object(&native).is_none());
+    }
+
+    #[test]
+    fn conversions_check_types() {
+        assert_eq!(i64::from_object(&Object::from(1.5)), None);
+        assert_eq!(f64::from_object(&Object::from(2)), Some(2.0));
+        assert_eq!(String::from_object(&Object::from(1)), None);
+        assert_eq!(bool::from_object(&Object::Nil), None);
+        assert_eq!(Vec::<i64>::from_object(&Object::Nil), Some(Vec::new()));
+        assert_eq!(Vec::<i64>::from_object(&vec![Object::from(1), Object::from("a")].into()), None);
+        assert_eq!(<(i64, i64)>::from_object(&vec![Object::from(1)].into()), None);
+        assert_eq!(<(i64, String)>::expected(), "list of (integer string)");
+        assert_eq!(Option::<i64>::from_object(&Object::from(1.5)), None);
+    }
+
+    #[test]
+    fn typed_functions_raise_arity_and_type_exceptions() {
+        let mut ctx = Context::new();
+        ctx.import_typed_function("add", |a: i64, b: i64| a + b);
+        ctx.import_typed_function("greet", |name: String, punct: Option<String>| {
+            format!("hello {}{}", name, punct.unwrap_or_default())
+        });
+        let error = "(catch e (list (exception_type e) (exception_message e)))";
+
+        assert_eq!(eval(&mut ctx, "(add 1 2)"), "3");
+        assert_eq!(eval(&mut ctx, &format!("(try (add 1) {})", error)), "List(arity add expects 2 arguments, got 1)");
+        assert_eq!(
+            eval(&mut ctx, &format!("(try (add 1 1.5) {})", error)),
+            "List(type add expects argument 2 to be integer, got number)"
+        );
+        assert_eq!(eval(&mut ctx, "(list (greet \"a\") (greet \"b\" \"!\"))"), "List(hello a hello b!)");
+        assert_eq!(
+            eval(&mut ctx, &format!("(try (greet) {})", error)),
+            "List(arity greet expects 1 to 2 arguments, got 0)"
+        );
+    }
+
+    #[test]
+    fn math_accepts_any_number_and_rejects_everything_else() {
+        let mut ctx = Context::new();
+        assert_eq!(eval(&mut ctx, "(import \"math\" as m) (list (m/sqrt 4) (m/pow 2 0.5) (m/atan2 0 1))"), "List(2 1.4142135623730951 0)");
+        assert_eq!(
+            eval(&mut ctx, "(import \"math\" as m) (try (m/sqrt \"4\") (catch e (exception_message e)))"),
+            "sqrt expects argument 1 to be number, got string"
+        );
+    }
+
+    #[test]
+    fn gfx_vectors_are_typed() {
+        let mut ctx = Context::new();
+        assert_eq!(
+            eval(&mut ctx, "(import \"gfx\" as g) (list (g/vec_x (g/vec2 1 2)) (g/vec_y (g/vec3 1 2 3)) (g/vec_z (g/vec3 1 2 3)))"),
+            "List(1 2 3)"
+        );
+        assert_eq!(
+            eval(&mut ctx, "(import \"gfx\" as g) (try (g/vec_x 1) (catch e (exception_message e)))"),
+            "vec_x expects argument 1 to be vec2 or vec3, got number"
+        );
+    }
+}
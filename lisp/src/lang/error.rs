@@ -0,0 +1,70 @@
+use std::io;
+
+use super::*;
+
+/// Why a module or call made from Rust failed.
+#[derive(Debug)]
+pub enum LispError {
+    /// The source could not be read as code.
+    Parse {
+        module: String,
+        position: Option<Position>,
+        message: String,
+    },
+    /// An exception escaped the code that was run.
+    Runtime(Exception),
+    /// A module file could not be read.
+    Io {
+        path: String,
+        error: io::Error,
+    },
+}
+
+impl LispError {
+    /// The exception behind a runtime error.
+    pub fn exception(&self) -> Option<&Exception> {
+        match *self {
+            LispError::Runtime(ref exception) => Some(exception),
+            _ => None,
+        }
+    }
+
+    /// Turns the error into the exception lisp code sees when it happens
+    /// inside an import or `eval`.
+    pub fn into_exception(self) -> Exception {
+        match self {
+            LispError::Runtime(exception) => exception,
+            error @ LispError::Parse { .. } => Exception::new("parse", error.describe()),
+            error @ LispError::Io { .. } => Exception::new("io", error.describe()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            LispError::Parse { ref module, position: Some(ref position), ref message } => {
+                format!("{} ({}:{})", message, module, position)
+            },
+            LispError::Parse { ref module, position: None, ref message } => format!("{} ({})", message, module),
+            LispError::Io { ref path, ref error } => format!("{}: {}", path, error),
+            LispError::Runtime(ref exception) => exception.to_string(),
+        }
+    }
+}
+
+impl From<Exception> for LispError {
+    fn from(exception: Exception) -> Self {
+        LispError::Runtime(exception)
+    }
+}
+
+impl ::std::fmt::Display for LispError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            LispError::Parse { .. } => write!(f, "parse: {}", self.describe()),
+            LispError::Io { .. } => write!(f, "io: {}", self.describe()),
+            LispError::Runtime(ref exception) => exception.fmt(f),
+        }
+    }
+}
+
+impl ::std::error::Error for LispError {}
@@ -3,49 +3,46 @@ use super::*;
 #[derive(Debug, Clone)]
 pub struct Expression {
     pub tokens: Vec<Token>,
+    pub spans: Vec<Span>,
+    pub location: Option<Location>,
 }
 
 impl Expression {
     pub fn new() -> Self {
-        Expression { tokens: vec![] }
+        Expression { tokens: vec![], spans: vec![], location: None }
+    }
+
+    pub fn with_location(location: Location) -> Self {
+        Expression { tokens: vec![], spans: vec![], location: Some(location) }
     }
 
     pub fn push(&mut self, token: Token) {
-        self.tokens.push(token);
+        let span = self.location.as_ref().map(|l| l.span.clone()).unwrap_or_else(Span::start);
+        self.push_with_span(token, span);
     }
 
-    pub fn eval(&self, ctx: &mut Context) -> Object {
-        let mut tokens = self.tokens.iter();
-
-        if let Some(callee) = tokens.next() {
-            match callee {
-                Token::Keyword(ref lang_item) => {
-                    lang_item.call(ctx, tokens.collect())
-                },
-                _ => {
-                    let callee = callee.eval(ctx);
-                    let args = tokens.map(|t| t.eval(ctx)).collect();
-
-                    callee.call(ctx, args)
-                }
-            }
-        } else {
-            Object::Nil
-        }
+    pub fn push_with_span(&mut self, token: Token, span: Span) {
+        self.tokens.push(token);
+        self.spans.push(span);
     }
-}
 
-impl From<Exception> for Expression {
-    fn from(other: Exception) -> Expression {
-        let lit: Literal = other.into();
-        Expression {
-            tokens: vec![lit.into()]
-        }
+    pub fn eval(&self, ctx: &mut Context) -> Object {
+        let token = match ctx.macroexpand(&Token::Expression(self.clone())) {
+            Ok(token) => token,
+            Err(exception) => return ctx.raise(exception),
+        };
+        let chunk = Compiler::compile_token(&token, Some(ctx.current_scope()));
+        vm::run(ctx, wrap(chunk))
     }
 }
 
 impl ::std::fmt::Display for Expression {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        if let Some(&Token::Keyword(Keyword::MapLiteral)) = self.tokens.first() {
+            let strings: Vec<_> = self.tokens[1..].iter().map(|t| t.to_string()).collect();
+            return write!(f, "{{{}}}", strings.join(" "));
+        }
+
         write!(f, "(")?;
         let mut first = true;
         for t in &self.tokens {
@@ -60,6 +57,24 @@ impl ::std::fmt::Display for Expression {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub module: Wrap<String>,
+    pub span: Span,
+}
+
+impl Location {
+    pub fn new(module: Wrap<String>, span: Span) -> Self {
+        Location { module, span }
+    }
+}
+
+impl ::std::fmt::Display for Location {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}:{}", self.module, self.span.start)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Token {
     Expression(Expression),
@@ -68,16 +83,6 @@ pub enum Token {
     Literal(Literal),
 }
 
-impl Token {
-    pub fn eval(&self, ctx: &mut Context) -> Object {
-        match *self {
-            Token::Ident(ident) => ctx.resolve_ident(ident),
-            Token::Literal(ref lit) => ctx.resolve_literal(lit),
-            Token::Keyword(ref keyword) => keyword.eval(ctx),
-            Token::Expression(ref exp) => exp.eval(ctx),
-        }
-    }
-}
 impl From<Expression> for Token {
     fn from(other: Expression) -> Token {
         Token::Expression(other)
@@ -102,6 +107,13 @@ impl From<Literal> for Token {
     }
 }
 
+impl Token {
+    /// Displays the token as source, with identifiers named.
+    pub fn repr<'a>(&'a self, ctx: &'a Context) -> Repr<'a, Token> {
+        Repr::new(self, ctx)
+    }
+}
+
 impl ::std::fmt::Display for Token {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match *self {
@@ -0,0 +1,414 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Weak;
+
+use super::*;
+
+// Collections start once this many scopes and traced objects are registered
+const MIN_THRESHOLD: usize = 10_000;
+
+/// A native object holding other values. Implementing it lets the collector
+/// see through the object, so cycles running through it can be freed.
+pub trait Trace {
+    /// Calls `visit` with every value the object holds.
+    fn trace(&self, visit: &mut dyn FnMut(&Object));
+
+    /// Drops every value the object holds. Only called on objects nothing
+    /// outside a cycle refers to any more.
+    fn clear(&self);
+}
+
+/// What the collector has done so far on this thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Number of collections run.
+    pub collections: usize,
+    /// Values freed by breaking cycles, over every collection.
+    pub freed: usize,
+    /// Scopes and traced native objects alive right now.
+    pub tracked: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Tracer {
+    trace: fn(&dyn Any, &mut dyn FnMut(&Object)),
+    clear: fn(&dyn Any),
+}
+
+fn trace_native<T: Trace + Any>(obj: &dyn Any, visit: &mut dyn FnMut(&Object)) {
+    if let Some(obj) = obj.downcast_ref::<T>() {
+        obj.trace(visit);
+    }
+}
+
+fn clear_native<T: Trace + Any>(obj: &dyn Any) {
+    if let Some(obj) = obj.downcast_ref::<T>() {
+        obj.clear();
+    }
+}
+
+// Every value that can take part in a cycle. Cycles can only be closed
+// through a mutable value, so scopes and traced native objects are enough to
+// find them all; the rest is reached from those.
+struct Heap {
+    scopes: Vec<WeakScope>,
+    natives: Vec<(Weak<dyn Any>, Tracer)>,
+    threshold: usize,
+    collections: usize,
+    freed: usize,
+}
+
+impl Heap {
+    fn new() -> Self {
+        Heap {
+            scopes: Vec::new(),
+            natives: Vec::new(),
+            threshold: MIN_THRESHOLD,
+            collections: 0,
+            freed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.scopes.len() + self.natives.len()
+    }
+
+    // Forgets freed values and returns the live ones
+    fn live(&mut self) -> (Vec<Scope>, Vec<(Wrap<dyn Any>, Tracer)>) {
+        let mut scopes = Vec::new();
+        self.scopes.retain(|scope| match scope.upgrade() {
+            Some(scope) => { scopes.push(scope); true },
+            None => false,
+        });
+
+        let mut natives = Vec::new();
+        self.natives.retain(|&(ref obj, tracer)| match obj.upgrade() {
+            Some(obj) => { natives.push((obj, tracer)); true },
+            None => false,
+        });
+
+        (scopes, natives)
+    }
+}
+
+thread_local!(static HEAP: RefCell<Heap> = RefCell::new(Heap::new()));
+// Read on every instruction, so kept apart from the heap
+thread_local!(static DUE: Cell<bool> = Cell::new(false));
+
+fn register<F: FnOnce(&mut Heap)>(f: F) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        f(&mut heap);
+        if heap.len() >= heap.threshold {
+            DUE.with(|due| due.set(true));
+        }
+    })
+}
+
+pub fn track_scope(scope: &Scope) {
+    register(|heap| heap.scopes.push(scope.downgrade()));
+}
+
+pub fn track_native<T: Trace + Any>(obj: &Wrap<T>) {
+    let tracer = Tracer {
+        trace: trace_native::<T>,
+        clear: clear_native::<T>,
+    };
+    let obj: Wrap<dyn Any> = obj.clone();
+    register(|heap| heap.natives.push((Wrap::downgrade(&obj), tracer)));
+}
+
+/// Whether enough has been allocated since the last collection to run
+/// another.
+pub fn is_due() -> bool {
+    DUE.with(Cell::get)
+}
+
+pub fn stats() -> GcStats {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        let (scopes, natives) = heap.live();
+        GcStats {
+            collections: heap.collections,
+            freed: heap.freed,
+            tracked: scopes.len() + natives.len(),
+        }
+    })
+}
+
+enum Node {
+    Scope(Scope),
+    Function(Wrap<Function>),
+    List(Wrap<Vector<Object>>),
+    // Vectors share their trie nodes and tail, so those are counted apart
+    // from the lists holding them
+    VectorNode(Wrap<VectorNode<Object>>),
+    VectorTail(Wrap<Vec<Object>>),
+    Map(Wrap<HashMap<MapKey, Object>>),
+    Exception(Wrap<Exception>),
+    Native(Wrap<dyn Any>, Tracer),
+}
+
+impl Node {
+    fn from_object(obj: &Object, tracers: &HashMap<usize, Tracer>) -> Option<Node> {
+        match *obj {
+            Object::Function(ref val) => Some(Node::Function(val.clone())),
+            Object::List(ref val) => Some(Node::List(val.clone())),
+            Object::Map(ref val) => Some(Node::Map(val.clone())),
+            Object::Exception(ref val) => Some(Node::Exception(val.clone())),
+            Object::NativeObject(ref val) => tracers
+                .get(&(Wrap::as_ptr(val) as *const u8 as usize))
+                .map(|tracer| Node::Native(val.clone(), *tracer)),
+            _ => None,
+        }
+    }
+
+    fn address(&self) -> usize {
+        match *self {
+            Node::Scope(ref val) => val.address(),
+            Node::Function(ref val) => Wrap::as_ptr(val) as usize,
+            Node::List(ref val) => Wrap::as_ptr(val) as usize,
+            Node::VectorNode(ref val) => Wrap::as_ptr(val) as usize,
+            Node::VectorTail(ref val) => Wrap::as_ptr(val) as usize,
+            Node::Map(ref val) => Wrap::as_ptr(val) as usize,
+            Node::Exception(ref val) => Wrap::as_ptr(val) as usize,
+            Node::Native(ref val, _) => Wrap::as_ptr(val) as *const u8 as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match *self {
+            Node::Scope(ref val) => val.strong_count(),
+            Node::Function(ref val) => Wrap::strong_count(val),
+            Node::List(ref val) => Wrap::strong_count(val),
+            Node::VectorNode(ref val) => Wrap::strong_count(val),
+            Node::VectorTail(ref val) => Wrap::strong_count(val),
+            Node::Map(ref val) => Wrap::strong_count(val),
+            Node::Exception(ref val) => Wrap::strong_count(val),
+            Node::Native(ref val, _) => Wrap::strong_count(val),
+        }
+    }
+
+    // One node per reference held, so shared values show up once per holder
+    fn children(&self, tracers: &HashMap<usize, Tracer>) -> Vec<Node> {
+        let mut children = Vec::new();
+        {
+            let mut visit = |obj: &Object| children.extend(Node::from_object(obj, tracers));
+            match *self {
+                Node::Scope(ref val) => val.trace(&mut visit),
+                Node::Function(_) => (),
+                Node::List(_) | Node::VectorNode(_) => (),
+                Node::VectorTail(ref val) => val.iter().for_each(&mut visit),
+                Node::Map(ref val) => val.values().for_each(&mut visit),
+                Node::Exception(ref val) => visit(val.get_payload()),
+                Node::Native(ref val, tracer) => (tracer.trace)(&**val, &mut visit),
+            }
+        }
+        match *self {
+            Node::Scope(ref val) => children.extend(val.parent().map(Node::Scope)),
+            Node::Function(ref val) => children.push(Node::Scope(val.scope.clone())),
+            Node::List(ref val) => {
+                children.push(Node::VectorNode(val.root().clone()));
+                children.push(Node::VectorTail(val.tail().clone()));
+            },
+            Node::VectorNode(ref val) => match **val {
+                VectorNode::Branch(ref nodes) => children.extend(nodes.iter().cloned().map(Node::VectorNode)),
+                VectorNode::Leaf(ref items) => children.extend(items.iter().filter_map(|obj| Node::from_object(obj, tracers))),
+            },
+            _ => (),
+        }
+
+        children
+    }
+
+    // Parts of another value rather than values of their own
+    fn is_internal(&self) -> bool {
+        match *self {
+            Node::VectorNode(_) | Node::VectorTail(_) => true,
+            _ => false,
+        }
+    }
+
+    fn clear(&self) {
+        match *self {
+            Node::Scope(ref val) => val.clear(),
+            Node::Native(ref val, tracer) => (tracer.clear)(&**val),
+            _ => (),
+        }
+    }
+}
+
+/// Frees every cycle of values nothing outside the cycle refers to, returning
+/// how many values were freed.
+///
+/// Every value reachable from a registered scope or traced native object is
+/// visited, and the references between them subtracted from their reference
+/// counts. Whatever is left was referenced from outside, by the VM, the
+/// context or the host, and is kept along with everything it reaches. The
+/// scopes and native objects in the rest are cleared, which breaks the
+/// cycles and lets reference counting free them.
+pub fn collect() -> usize {
+    DUE.with(|due| due.set(false));
+    let (scopes, natives) = HEAP.with(|heap| heap.borrow_mut().live());
+    let tracers: HashMap<usize, Tracer> = natives
+        .iter()
+        .map(|&(ref obj, tracer)| (Wrap::as_ptr(obj) as *const u8 as usize, tracer))
+        .collect();
+
+    // The collector holds exactly one reference to every node, and records
+    // the references between them by index
+    let mut nodes: Vec<Node> = scopes
+        .into_iter()
+        .map(Node::Scope)
+        .chain(natives.into_iter().map(|(obj, tracer)| Node::Native(obj, tracer)))
+        .collect();
+    let mut index: HashMap<usize, usize> = nodes.iter().enumerate().map(|(idx, node)| (node.address(), idx)).collect();
+    // The references held by node `n` are `edges[offsets[n]..offsets[n + 1]]`
+    let mut edges: Vec<usize> = Vec::new();
+    let mut offsets: Vec<usize> = vec![0];
+    while offsets.len() <= nodes.len() {
+        for child in nodes[offsets.len() - 1].children(&tracers) {
+            let next = nodes.len();
+            let target = *index.entry(child.address()).or_insert(next);
+            if target == next {
+                nodes.push(child);
+            }
+            edges.push(target);
+        }
+        offsets.push(edges.len());
+    }
+
+    let mut external: Vec<usize> = nodes.iter().map(|node| node.strong_count() - 1).collect();
+    for &target in &edges {
+        external[target] = external[target].saturating_sub(1);
+    }
+
+    let mut reachable = vec![false; nodes.len()];
+    let mut pending: Vec<usize> = (0..nodes.len()).filter(|&idx| external[idx] > 0).collect();
+    while let Some(idx) = pending.pop() {
+        if reachable[idx] {
+            continue;
+        }
+        reachable[idx] = true;
+        pending.extend(edges[offsets[idx]..offsets[idx + 1]].iter().filter(|&&target| !reachable[target]));
+    }
+
+    let garbage: Vec<Node> = nodes
+        .into_iter()
+        .zip(reachable)
+        .filter(|&(_, reachable)| !reachable)
+        .map(|(node, _)| node)
+        .collect();
+    let freed = garbage.iter().filter(|node| !node.is_internal()).count();
+    for node in &garbage {
+        node.clear();
+    }
+    drop(garbage);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.live();
+        heap.threshold = ::std::cmp::max(MIN_THRESHOLD, heap.len() * 2);
+        heap.collections += 1;
+        heap.freed += freed;
+    });
+
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    const MAKE: &str = "(func make (n) ((let v n) (func get () (v)) get))";
+
+    struct Holder(RefCell<Vec<Object>>);
+
+    impl Trace for Holder {
+        fn trace(&self, visit: &mut dyn FnMut(&Object)) {
+            self.0.borrow().iter().for_each(visit);
+        }
+
+        fn clear(&self) {
+            self.0.borrow_mut().clear();
+        }
+    }
+
+    fn closure(ctx: &mut Context, n: i64) -> Object {
+        ctx.try_eval_module(&format!("{} (make {})", MAKE, n)).expect("makes a closure")
+    }
+
+    fn downgrade(obj: &Object) -> Weak<Function> {
+        match *obj {
+            Object::Function(ref val) => Wrap::downgrade(val),
+            _ => panic!("expected a function, got {:?}", obj),
+        }
+    }
+
+    #[test]
+    fn closures_stored_in_their_own_scope_are_freed() {
+        let mut ctx = Context::new();
+        let get = downgrade(&closure(&mut ctx, 1));
+        assert!(get.upgrade().is_some(), "the cycle keeps the closure alive");
+
+        assert!(ctx.collect_garbage() >= 2);
+        assert!(get.upgrade().is_none());
+    }
+
+    #[test]
+    fn closures_referenced_from_outside_are_kept() {
+        let mut ctx = Context::new();
+        let global = closure(&mut ctx, 1);
+        let from_global = downgrade(&global);
+        ctx.set_global("kept", global);
+
+        let holder = Object::create_traced_object(Holder(RefCell::new(vec![closure(&mut ctx, 2)])));
+        let from_native = match holder {
+            Object::NativeObject(ref val) => downgrade(&val.downcast_ref::<Holder>().expect("a holder").0.borrow()[0]),
+            _ => unreachable!(),
+        };
+
+        ctx.collect_garbage();
+        assert!(from_global.upgrade().is_some());
+        assert!(from_native.upgrade().is_some());
+        assert_eq!(ctx.try_eval_module("(kept)").expect("still callable"), Object::from(1));
+
+        drop(holder);
+        ctx.collect_garbage();
+        assert!(from_native.upgrade().is_none());
+    }
+
+    #[test]
+    fn cycles_through_traced_natives_are_freed() {
+        let mut ctx = Context::new();
+        let holder = Wrap::new(Holder(RefCell::new(Vec::new())));
+        track_native(&holder);
+        ctx.set_global("holder", Object::NativeObject(holder.clone()));
+        let get = ctx.try_eval_module(&format!("{} (make holder)", MAKE)).expect("makes a closure");
+        let weak = downgrade(&get);
+        holder.0.borrow_mut().push(get);
+        ctx.set_global("holder", Object::Nil);
+        drop(holder);
+        assert!(weak.upgrade().is_some(), "the cycle keeps the closure alive");
+
+        assert!(ctx.collect_garbage() >= 3);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn collecting_mid_call_keeps_values_held_by_live_frames() {
+        let source = format!(
+            "{}
+            (func churn () ((let a 0) (make 3) (a)))
+            (func deep (f n) (if (> n 0) (+ 0 (deep f (- n 1))) ((let done (churn)) (+ (* (gc) 1000) (f)))))
+            (func run () ((let pending (list (make 1) (deep (make 2) 50))) (list ((nth pending 0)) (nth pending 1))))
+            (run)",
+            MAKE
+        );
+        let result = Context::new().try_eval_module(&source).expect("runs");
+        assert_eq!(result, Object::from(vec![Object::from(1), Object::from(2002)]));
+    }
+}
@@ -0,0 +1,363 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use super::*;
+
+// Reading the clock on every instruction would dominate tight loops
+const DEADLINE_INTERVAL: u64 = 1024;
+// Tracked allocations are swept for freed ones at least this often
+const MIN_SWEEP: usize = 1024;
+
+// A value counted against the memory limit, held weakly so it can be freed
+enum Tracked {
+    String(Weak<String>),
+    VectorNode(Weak<VectorNode<Object>>),
+    VectorTail(Weak<Vec<Object>>),
+    Map(Weak<HashMap<MapKey, Object>>),
+    Native(Weak<dyn Any>),
+    Quote(Weak<Token>),
+    Scope(WeakScope),
+}
+
+impl Tracked {
+    fn new(obj: &Object) -> Option<Self> {
+        match *obj {
+            Object::String(ref val) => Some(Tracked::String(Wrap::downgrade(val))),
+            Object::Map(ref val) => Some(Tracked::Map(Wrap::downgrade(val))),
+            Object::NativeObject(ref val) => Some(Tracked::Native(Wrap::downgrade(val))),
+            Object::Quote(ref val) => Some(Tracked::Quote(val.downgrade())),
+            _ => None,
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        match *self {
+            Tracked::String(ref val) => val.strong_count() > 0,
+            Tracked::VectorNode(ref val) => val.strong_count() > 0,
+            Tracked::VectorTail(ref val) => val.strong_count() > 0,
+            Tracked::Map(ref val) => val.strong_count() > 0,
+            Tracked::Native(ref val) => val.strong_count() > 0,
+            Tracked::Quote(ref val) => val.strong_count() > 0,
+            Tracked::Scope(ref val) => val.is_live(),
+        }
+    }
+}
+
+impl ::std::fmt::Debug for Tracked {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Tracked(live: {})", self.is_live())
+    }
+}
+
+/// Stops a running context from another thread or a signal handler.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// Makes the context unwind with an "interrupted" exception at its next
+    /// instruction. Has no effect if nothing is running.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+}
+
+/// How long a single evaluation may run and how much it may allocate.
+#[derive(Debug)]
+pub struct Limits {
+    step_limit: Option<u64>,
+    deadline: Option<Instant>,
+    memory_limit: Option<usize>,
+    steps: u64,
+    allocated: usize,
+    tracked: Vec<(Tracked, usize)>,
+    // List versions share most of their nodes, so those are tracked once
+    // each by address instead of once per list
+    vector_nodes: HashMap<usize, (Tracked, usize)>,
+    sweep_at: usize,
+    interrupt: Arc<AtomicBool>,
+    tripped: bool,
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Limits {
+            step_limit: None,
+            deadline: None,
+            memory_limit: None,
+            steps: 0,
+            allocated: 0,
+            tracked: Vec::new(),
+            vector_nodes: HashMap::new(),
+            sweep_at: MIN_SWEEP,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            tripped: false,
+        }
+    }
+
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+        if limit.is_none() {
+            self.tracked.clear();
+            self.vector_nodes.clear();
+            self.allocated = 0;
+        }
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            flag: self.interrupt.clone(),
+        }
+    }
+
+    pub fn tripped(&self) -> bool {
+        self.tripped
+    }
+
+    // Every evaluation started from outside the VM gets the full budget, and
+    // interrupts sent while nothing was running are forgotten
+    pub fn start(&mut self) {
+        self.steps = 0;
+        self.interrupt.store(false, Ordering::SeqCst);
+    }
+
+    pub fn finish(&mut self) {
+        self.tripped = false;
+        self.interrupt.store(false, Ordering::SeqCst);
+    }
+
+    /// Counts one instruction, returning the exception to raise if the
+    /// evaluation has to stop.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        self.steps += 1;
+
+        let reason = if self.interrupt.load(Ordering::Relaxed) {
+            Some("evaluation interrupted")
+        } else if self.step_limit.map(|limit| self.steps > limit).unwrap_or(false) {
+            Some("step limit exceeded")
+        } else if self.steps % DEADLINE_INTERVAL == 0 && self.deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+            Some("deadline exceeded")
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                self.tripped = true;
+                Err(Exception::new("interrupted", reason))
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if allocating `bytes` more would go over the memory limit even
+    /// after forgetting values that have since been freed.
+    pub fn check_allocation(&mut self, bytes: usize) -> Result<(), Exception> {
+        let limit = match self.memory_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.allocated.saturating_add(bytes) > limit {
+            self.sweep();
+        }
+        if self.allocated.saturating_add(bytes) > limit {
+            return Err(Exception::new("memory", format!("out of memory: allocation limit of {} bytes exceeded", limit)));
+        }
+
+        Ok(())
+    }
+
+    /// Counts a newly allocated value against the memory limit for as long as
+    /// it stays alive.
+    pub fn track(&mut self, obj: &Object) -> Result<(), Exception> {
+        if self.memory_limit.is_none() {
+            return Ok(());
+        }
+        if let Object::List(ref list) = *obj {
+            return self.track_vector(list);
+        }
+        match Tracked::new(obj) {
+            Some(tracked) => self.charge(tracked, obj.allocation_size()),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts the slots of a scope a function call created, which closures
+    /// can keep alive long after the call returns.
+    pub fn track_scope(&mut self, scope: &Scope) -> Result<(), Exception> {
+        if self.memory_limit.is_none() {
+            return Ok(());
+        }
+        self.charge(Tracked::Scope(scope.downgrade()), scope.allocation_size())
+    }
+
+    fn charge(&mut self, tracked: Tracked, bytes: usize) -> Result<(), Exception> {
+        if bytes == 0 {
+            return Ok(());
+        }
+        self.check_allocation(bytes)?;
+
+        self.tracked.push((tracked, bytes));
+        self.allocated += bytes;
+        self.maybe_sweep();
+
+        Ok(())
+    }
+
+    // Only charges the nodes of a list that no tracked list shares yet, so
+    // updating or appending to a list costs the path it copied
+    fn track_vector(&mut self, list: &Vector<Object>) -> Result<(), Exception> {
+        let mut fresh = Vec::new();
+        let tail = list.tail();
+        if !self.is_tracked_node(Rc::as_ptr(tail) as usize) {
+            let bytes = tail.len() * mem::size_of::<Object>();
+            fresh.push((Rc::as_ptr(tail) as usize, Tracked::VectorTail(Rc::downgrade(tail)), bytes));
+        }
+        let mut pending = vec![list.root()];
+        while let Some(node) = pending.pop() {
+            let address = Rc::as_ptr(node) as usize;
+            if self.is_tracked_node(address) {
+                continue;
+            }
+            let bytes = match **node {
+                VectorNode::Branch(ref children) => {
+                    pending.extend(children.iter());
+                    children.len() * mem::size_of::<Rc<VectorNode<Object>>>()
+                },
+                VectorNode::Leaf(ref items) => items.len() * mem::size_of::<Object>(),
+            };
+            fresh.push((address, Tracked::VectorNode(Rc::downgrade(node)), bytes));
+        }
+
+        let bytes = fresh.iter().map(|&(_, _, bytes)| bytes).sum();
+        self.check_allocation(bytes)?;
+
+        for (address, tracked, bytes) in fresh {
+            if bytes > 0 {
+                self.vector_nodes.insert(address, (tracked, bytes));
+            }
+        }
+        self.allocated += bytes;
+        self.maybe_sweep();
+
+        Ok(())
+    }
+
+    // A dead node's address can be reused by a new one, which is then fresh
+    fn is_tracked_node(&self, address: usize) -> bool {
+        self.vector_nodes.get(&address).map(|&(ref tracked, _)| tracked.is_live()).unwrap_or(false)
+    }
+
+    fn maybe_sweep(&mut self) {
+        let count = self.tracked.len() + self.vector_nodes.len();
+        if count >= self.sweep_at {
+            self.sweep();
+            self.sweep_at = ::std::cmp::max(MIN_SWEEP, (self.tracked.len() + self.vector_nodes.len()) * 2);
+        }
+    }
+
+    fn sweep(&mut self) {
+        self.tracked.retain(|&(ref tracked, _)| tracked.is_live());
+        self.vector_nodes.retain(|_, &mut (ref tracked, _)| tracked.is_live());
+        self.allocated = self.tracked.iter().map(|&(_, bytes)| bytes).sum::<usize>()
+            + self.vector_nodes.values().map(|&(_, bytes)| bytes).sum::<usize>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn interrupts_from_another_thread_stop_the_evaluation() {
+        let mut ctx = Context::new();
+        let handle = ctx.interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            handle.interrupt();
+        });
+
+        match ctx.try_eval_module("(loop true 1)") {
+            Err(LispError::Runtime(exception)) => assert_eq!(exception.get_kind(), "interrupted"),
+            other => panic!("expected an interrupt, got {:?}", other.map(|_| ())),
+        }
+        interrupter.join().expect("interrupter finishes");
+        assert_eq!(ctx.try_eval_module("(+ 1 2)").expect("runs again"), Object::from(3));
+    }
+
+    #[test]
+    fn interrupts_while_idle_are_forgotten() {
+        let mut ctx = Context::new();
+        ctx.interrupt_handle().interrupt();
+        assert_eq!(ctx.try_eval_module("(+ 1 2)").expect("runs"), Object::from(3));
+    }
+
+    fn memory_error(ctx: &mut Context, source: &str) -> Option<String> {
+        match ctx.try_eval_module(source) {
+            Err(LispError::Runtime(exception)) => Some(exception.get_kind().to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn closures_are_charged_for_the_scopes_they_keep() {
+        let mut ctx = Context::new();
+        ctx.set_memory_limit(Some(64 * 1024));
+        let chain = "(let n 0) (let f (lambda () (1))) (func chain (g pad) (lambda () (g)))";
+
+        // Each closure only keeps the last one alive, so the scopes are freed
+        let source = format!("{} (loop (< n 20000) ((set n (+ n 1)) (let g (chain f n)))) n", chain);
+        assert_eq!(ctx.try_eval_module(source).expect("stays under the limit"), Object::from(20000));
+
+        let source = format!("{} (loop (< n 20000) ((set n (+ n 1)) (set f (chain f n))))", chain);
+        assert_eq!(memory_error(&mut ctx, &source).as_ref().map(String::as_str), Some("memory"));
+    }
+
+    #[test]
+    fn quasiquotes_are_charged_for_the_code_they_build() {
+        let mut ctx = Context::new();
+        ctx.set_memory_limit(Some(64 * 1024));
+        let source = "(let n 0) (let code (quote nil)) (loop (< n 20000) ((set n (+ n 1)) (set code `(wrap ,code))))";
+        assert_eq!(memory_error(&mut ctx, source).as_ref().map(String::as_str), Some("memory"));
+    }
+
+    #[test]
+    fn list_updates_are_charged_for_the_nodes_they_copy() {
+        let mut ctx = Context::new();
+        ctx.set_memory_limit(Some(1 << 20));
+        let source = "
+            (let l (range 4096))
+            (let versions (list))
+            (let i 0)
+            (loop (< i 500) (
+                (set l (nth l (% (* i 37) 4096) i))
+                (set versions (cons l versions))
+                (set i (+ i 1))
+            ))
+            (len versions)
+        ";
+        assert_eq!(ctx.try_eval_module(source).expect("stays under the limit"), Object::from(500));
+        assert!(ctx.allocated() < 1 << 20, "{} bytes", ctx.allocated());
+    }
+}
@@ -40,7 +40,7 @@ impl ::std::fmt::Display for Literal {
             Boolean(val) => write!(f, "{}", val)?,
             Nil => write!(f, "nil")?,
             Number(val) => write!(f, "{}", val)?,
-            String(ref val) => write!(f, "\"{}\"", val)?,
+            String(ref val) => printer::write_string(f, val)?,
             Exception(ref _ex) => write!(f, "[exception]")?,
         }
 
@@ -54,6 +54,15 @@ pub enum Number {
     Float(f64),
 }
 
+impl Number {
+    pub fn as_float(&self) -> f64 {
+        match *self {
+            Number::Integer(value) => value as f64,
+            Number::Float(value) => value,
+        }
+    }
+}
+
 impl From<i64> for Number {
     fn from(other: i64) -> Self {
         Number::Integer(other)
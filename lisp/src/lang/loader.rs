@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+/// Finds and reads the source of modules that are not native.
+pub trait ModuleLoader {
+    /// Finds the module `path` imported by the module named `importer`, or
+    /// from the top level when there is none, falling back to the context's
+    /// `search_paths`. The name returned identifies the module: it is cached,
+    /// and checked for circular imports, by it.
+    fn resolve(&self, path: &str, importer: Option<&str>, search_paths: &[PathBuf]) -> Result<String, LispError>;
+
+    /// Reads the source of a module `resolve` found.
+    fn load(&self, name: &str) -> Result<String, LispError>;
+}
+
+/// Loads modules from disk. Relative paths are looked up next to the module
+/// importing them, or the working directory at the top level, and then in
+/// each search path.
+#[derive(Debug, Clone, Default)]
+pub struct FileSystemLoader;
+
+impl FileSystemLoader {
+    pub fn new() -> Self {
+        FileSystemLoader
+    }
+}
+
+impl ModuleLoader for FileSystemLoader {
+    fn resolve(&self, path: &str, importer: Option<&str>, search_paths: &[PathBuf]) -> Result<String, LispError> {
+        let relative_to = importer
+            .and_then(|importer| Path::new(importer).parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        let mut candidates = vec![relative_to.join(path)];
+        if Path::new(path).is_relative() {
+            candidates.extend(search_paths.iter().map(|dir| dir.join(path)));
+        }
+
+        let found = candidates
+            .into_iter()
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| Exception::new("import", format!("module not found: {}", path)))?;
+        let canonical = found
+            .canonicalize()
+            .map_err(|error| LispError::Io { path: path.to_string(), error })?;
+
+        Ok(canonical.to_string_lossy().into_owned())
+    }
+
+    fn load(&self, name: &str) -> Result<String, LispError> {
+        ::std::fs::read_to_string(name).map_err(|error| LispError::Io { path: name.to_string(), error })
+    }
+}
+
+/// Serves modules from sources held in memory, named by path. Clones share
+/// the same modules, so the host can keep one to add modules later.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLoader {
+    modules: Wrap<RefCell<HashMap<String, String>>>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces the module at `path`. Modules already imported keep
+    /// their old namespace.
+    pub fn add_module<P: AsRef<str>, S: Into<String>>(&self, path: P, source: S) {
+        self.modules.borrow_mut().insert(normalize(path.as_ref()), source.into());
+    }
+
+    pub fn with_module<P: AsRef<str>, S: Into<String>>(self, path: P, source: S) -> Self {
+        self.add_module(path, source);
+        self
+    }
+}
+
+impl ModuleLoader for MemoryLoader {
+    fn resolve(&self, path: &str, importer: Option<&str>, search_paths: &[PathBuf]) -> Result<String, LispError> {
+        let relative = importer
+            .and_then(|importer| importer.rfind('/').map(|split| &importer[..split]))
+            .map(|dir| normalize(&format!("{}/{}", dir, path)));
+        let searched = search_paths
+            .iter()
+            .map(|dir| normalize(&format!("{}/{}", dir.to_string_lossy(), path)));
+
+        let modules = self.modules.borrow();
+        relative
+            .into_iter()
+            .chain(Some(normalize(path)))
+            .chain(searched)
+            .find(|candidate| modules.contains_key(candidate))
+            .ok_or_else(|| Exception::new("import", format!("module not found: {}", path)).into())
+    }
+
+    fn load(&self, name: &str) -> Result<String, LispError> {
+        self.modules
+            .borrow()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Exception::new("import", format!("module not found: {}", name)).into())
+    }
+}
+
+// Resolves `.` and `..` so every spelling of a path names the same module
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => { parts.pop(); },
+            part => parts.push(part),
+        }
+    }
+
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn normalize_resolves_dots() {
+        assert_eq!(normalize("a/./b/../c.lisp"), "a/c.lisp");
+        assert_eq!(normalize("./a//b.lisp"), "a/b.lisp");
+        assert_eq!(normalize("a/../../b.lisp"), "b.lisp");
+        assert_eq!(normalize("/lib/a.lisp"), "lib/a.lisp");
+    }
+
+    #[test]
+    fn memory_loader_resolves_like_the_filesystem() {
+        let loader = MemoryLoader::new()
+            .with_module("main.lisp", "main")
+            .with_module("lib/util.lisp", "util")
+            .with_module("vendor/dep.lisp", "dep");
+        let search = [PathBuf::from("vendor")];
+        let resolve = |path, importer| loader.resolve(path, importer, &search).ok();
+
+        assert_eq!(resolve("lib/util.lisp", None), Some("lib/util.lisp".to_string()));
+        assert_eq!(resolve("util.lisp", Some("lib/other.lisp")), Some("lib/util.lisp".to_string()));
+        assert_eq!(resolve("../main.lisp", Some("lib/util.lisp")), Some("main.lisp".to_string()));
+        assert_eq!(resolve("dep.lisp", Some("lib/util.lisp")), Some("vendor/dep.lisp".to_string()));
+        assert_eq!(resolve("missing.lisp", None), None);
+        assert_eq!(loader.load("lib/util.lisp").ok(), Some("util".to_string()));
+    }
+
+    #[test]
+    fn filesystem_loader_resolves_to_canonical_paths() {
+        let dir = ::std::env::temp_dir().join(format!("lisp-loader-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::create_dir_all(dir.join("vendor")).unwrap();
+        fs::write(dir.join("main.lisp"), "main").unwrap();
+        fs::write(dir.join("lib/util.lisp"), "util").unwrap();
+        fs::write(dir.join("vendor/dep.lisp"), "dep").unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let name = |path: &str| dir.join(path).to_string_lossy().into_owned();
+
+        let loader = FileSystemLoader::new();
+        let search = [dir.join("vendor")];
+        let importer = name("lib/util.lisp");
+        let resolve = |path: &str, importer: Option<&str>| loader.resolve(path, importer, &search).ok();
+
+        assert_eq!(resolve(&name("lib/../main.lisp"), None), Some(name("main.lisp")));
+        assert_eq!(resolve("../main.lisp", Some(&importer)), Some(name("main.lisp")));
+        assert_eq!(resolve("dep.lisp", Some(&importer)), Some(name("vendor/dep.lisp")));
+        assert_eq!(resolve("missing.lisp", Some(&importer)), None);
+        assert_eq!(loader.load(&importer).ok(), Some("util".to_string()));
+    }
+}
@@ -0,0 +1,270 @@
+use super::*;
+
+/// Expands every macro in a module before it is compiled, defining macros in
+/// the order they appear so later code can use them.
+pub fn expand_module(ctx: &mut Context, module: &Expression) -> Result<Expression, Exception> {
+    let mut expanded = module.clone();
+    for token in &mut expanded.tokens {
+        *token = expand(ctx, token)?;
+    }
+
+    Ok(expanded)
+}
+
+pub fn expand(ctx: &mut Context, token: &Token) -> Result<Token, Exception> {
+    match *token {
+        Token::Expression(ref exp) => expand_expression(ctx, exp),
+        _ => Ok(token.clone()),
+    }
+}
+
+fn expand_expression(ctx: &mut Context, exp: &Expression) -> Result<Token, Exception> {
+    match exp.tokens.first() {
+        Some(&Token::Keyword(Keyword::Defmacro)) => {
+            define(ctx, exp)?;
+            Ok(Token::Literal(Literal::Nil))
+        },
+        Some(&Token::Keyword(Keyword::Quote)) => Ok(Token::Expression(exp.clone())),
+        Some(&Token::Keyword(Keyword::Quasiquote)) => {
+            let mut expanded = exp.clone();
+            for token in expanded.tokens.iter_mut().skip(1) {
+                *token = expand_template(ctx, token)?;
+            }
+
+            Ok(Token::Expression(expanded))
+        },
+        Some(&Token::Ident(ident)) if ctx.get_macro(ident).is_some() => {
+            let func = ctx.get_macro(ident).expect("macro");
+            let args = exp.tokens[1..]
+                .iter()
+                .map(|token| Quote::new(token.clone()).into())
+                .collect();
+            let result = ctx.catch(|ctx| vm::call_function(ctx, &func, args))?;
+            let token = code_from_object(&result)?;
+
+            expand(ctx, &token)
+        },
+        _ => {
+            let mut expanded = exp.clone();
+            for token in &mut expanded.tokens {
+                *token = expand(ctx, token)?;
+            }
+
+            Ok(Token::Expression(expanded))
+        },
+    }
+}
+
+// Only the unquoted parts of a template are code; the rest is left as written.
+fn expand_template(ctx: &mut Context, token: &Token) -> Result<Token, Exception> {
+    let exp = match *token {
+        Token::Expression(ref exp) => exp,
+        _ => return Ok(token.clone()),
+    };
+
+    let mut expanded = exp.clone();
+    match exp.tokens.first() {
+        Some(&Token::Keyword(Keyword::Unquote)) | Some(&Token::Keyword(Keyword::UnquoteSplicing)) => {
+            for token in expanded.tokens.iter_mut().skip(1) {
+                *token = expand(ctx, token)?;
+            }
+        },
+        Some(&Token::Keyword(Keyword::Quasiquote)) => (),
+        _ => {
+            for token in &mut expanded.tokens {
+                *token = expand_template(ctx, token)?;
+            }
+        },
+    }
+
+    Ok(Token::Expression(expanded))
+}
+
+// `(defmacro name (params) body)` is compiled like a lambda and run right away.
+fn define(ctx: &mut Context, exp: &Expression) -> Result<(), Exception> {
+    let name = match exp.tokens.get(1) {
+        Some(&Token::Ident(ident)) => ident,
+        _ => return Err(Exception::new("syntax", "invalid defmacro")),
+    };
+
+    let mut lambda = exp.clone();
+    lambda.tokens.drain(..2);
+    lambda.spans.drain(..2);
+    match (lambda.tokens.first(), lambda.tokens.get(1), lambda.tokens.get(2)) {
+        (Some(&Token::Expression(_)), Some(&Token::Expression(_)), None) |
+        (Some(&Token::Expression(_)), None, None) => (),
+        _ => return Err(Exception::new("syntax", "invalid defmacro")),
+    }
+    lambda.tokens.insert(0, Token::Keyword(Keyword::Lambda));
+    lambda.spans.insert(0, exp.spans[0].clone());
+
+    let lambda = match expand_expression(ctx, &lambda)? {
+        Token::Expression(lambda) => lambda,
+        _ => return Err(Exception::new("syntax", "invalid defmacro")),
+    };
+    let chunk = wrap(Compiler::compile(&lambda, Some(ctx.current_scope())));
+    match ctx.catch(|ctx| vm::run(ctx, chunk))? {
+        Object::Function(func) => {
+            ctx.define_macro(name, func);
+            Ok(())
+        },
+        _ => Err(Exception::new("syntax", "invalid defmacro")),
+    }
+}
+
+/// Converts a value produced by a macro or quasiquote back into code. Quotes
+/// stand for the code they hold and lists become expressions.
+pub fn code_from_object(obj: &Object) -> Result<Token, Exception> {
+    match *obj {
+        Object::Nil => Ok(Token::Literal(Literal::Nil)),
+        Object::Boolean(val) => Ok(Token::Literal(Literal::Boolean(val))),
+        Object::Number(val) => Ok(Token::Literal(Literal::Number(val))),
+        Object::String(ref val) => Ok(Token::Literal(Literal::String((**val).clone()))),
+        Object::Exception(ref val) => Ok(Token::Literal(Literal::Exception((**val).clone()))),
+        Object::Quote(ref quote) => Ok(quote.token().clone()),
+        Object::List(ref items) => {
+            let mut exp = Expression::new();
+            for item in items.iter() {
+                exp.push(code_from_object(item)?);
+            }
+
+            Ok(Token::Expression(exp))
+        },
+        Object::Map(ref map) => {
+            let mut exp = Expression::new();
+            exp.push(Token::Keyword(Keyword::MapLiteral));
+            for (key, value) in sorted_entries(map) {
+                exp.push(code_from_object(&key.to_object())?);
+                exp.push(code_from_object(value)?);
+            }
+
+            Ok(Token::Expression(exp))
+        },
+        Object::Function(_) | Object::NativeFunction(_) | Object::NativeObject(_) => {
+            Err(Exception::new("syntax", format!("{} cannot be used as code", obj.type_name())))
+        },
+    }
+}
+
+/// Substitutes `values` for the unquoted parts of a quasiquote template.
+pub fn fill_template<I: Iterator<Item = Object>>(template: &Token, values: &mut I) -> Result<Token, Exception> {
+    let exp = match *template {
+        Token::Expression(ref exp) => exp,
+        _ => return Ok(template.clone()),
+    };
+
+    match exp.tokens.first() {
+        Some(&Token::Keyword(Keyword::Unquote)) | Some(&Token::Keyword(Keyword::UnquoteSplicing)) => {
+            return code_from_object(&values.next().unwrap_or(Object::Nil));
+        },
+        Some(&Token::Keyword(Keyword::Quasiquote)) => return Ok(template.clone()),
+        _ => (),
+    }
+
+    let mut filled = exp.clone();
+    filled.tokens.clear();
+    filled.spans.clear();
+    for (token, span) in exp.tokens.iter().zip(&exp.spans) {
+        match *token {
+            Token::Expression(ref inner) if is_splice(inner) => {
+                match values.next().unwrap_or(Object::Nil) {
+                    Object::List(items) => {
+                        for item in items.iter() {
+                            filled.push_with_span(code_from_object(item)?, span.clone());
+                        }
+                    },
+                    // Macro arguments are quoted code, which splices as
+                    // its elements
+                    Object::Quote(ref quote) => match *quote.token() {
+                        Token::Expression(ref code) => {
+                            for token in &code.tokens {
+                                filled.push_with_span(token.clone(), span.clone());
+                            }
+                        },
+                        ref token => filled.push_with_span(token.clone(), span.clone()),
+                    },
+                    Object::Nil => (),
+                    value => filled.push_with_span(code_from_object(&value)?, span.clone()),
+                }
+            },
+            _ => filled.push_with_span(fill_template(token, values)?, span.clone()),
+        }
+    }
+
+    Ok(Token::Expression(filled))
+}
+
+fn is_splice(exp: &Expression) -> bool {
+    match exp.tokens.first() {
+        Some(&Token::Keyword(Keyword::UnquoteSplicing)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A module is a block, so each source starts with a value rather than a
+    // function
+    fn eval(ctx: &mut Context, source: &str) -> Object {
+        ctx.eval_module(format!("(let result nil) {}", source))
+    }
+
+    #[test]
+    fn macros_expand_before_evaluation() {
+        let mut ctx = Context::new();
+        let source = "
+            (defmacro unless (cond body) `(if ,cond nil ,body))
+            (list (unless false 5) (unless true 5))";
+        assert_eq!(eval(&mut ctx, source).to_string(), "List(5 nil)");
+    }
+
+    #[test]
+    fn unquote_splicing_inlines_a_list() {
+        let mut ctx = Context::new();
+        let source = "
+            (defmacro sum (items) `(+ 100 ,@items))
+            (sum (1 2 3))";
+        assert_eq!(eval(&mut ctx, source).to_string(), "106");
+    }
+
+    #[test]
+    fn macroexpand_shows_the_expansion() {
+        let mut ctx = Context::new();
+        let expanded = eval(&mut ctx, "(defmacro unless (cond body) `(if ,cond nil ,body)) (macroexpand (quote (unless c x)))");
+        let (c, x) = (ctx.get_or_add_ident("c"), ctx.get_or_add_ident("x"));
+        let tokens = match expanded {
+            Object::Quote(ref quote) => match *quote.token() {
+                Token::Expression(ref exp) => exp.tokens.clone(),
+                ref other => panic!("expected an expression, got {}", other),
+            },
+            ref other => panic!("expected a quote, got {}", other),
+        };
+        match (&tokens[0], &tokens[1], &tokens[2], &tokens[3]) {
+            (&Token::Keyword(Keyword::If), &Token::Ident(cond), &Token::Literal(Literal::Nil), &Token::Ident(body)) => {
+                assert_eq!((cond, body), (c, x));
+            },
+            _ => panic!("unexpected expansion {:?}", tokens),
+        }
+    }
+
+    #[test]
+    fn gensyms_keep_macro_variables_apart() {
+        let mut ctx = Context::new();
+        let source = "
+            (defmacro twice (body) ((let tmp (gensym)) `((let ,tmp ,body) (+ ,tmp ,tmp))))
+            (let tmp 10)
+            (list (twice 4) tmp)";
+        assert_eq!(eval(&mut ctx, source).to_string(), "List(8 10)");
+    }
+
+    #[test]
+    fn macros_are_shared_by_every_module_of_a_context() {
+        let mut ctx = Context::new();
+        eval(&mut ctx, "(defmacro first_of (a b) `,a)");
+        assert_eq!(eval(&mut ctx, "(first_of 1 2)").to_string(), "1");
+        eval(&mut ctx, "(defmacro first_of (a b) `,b)");
+        assert_eq!(eval(&mut ctx, "(first_of 1 2)").to_string(), "2");
+    }
+}
@@ -1,18 +1,39 @@
 use std::collections::HashMap;
 use std::rc::Rc;
 
+mod compile;
 mod context;
+mod convert;
+mod error;
 mod expression;
+mod gc;
 mod keyword;
+mod limits;
+mod loader;
 mod literal;
+mod macros;
 mod object;
 mod parse;
+mod printer;
+mod scope;
+mod vector;
+mod vm;
 
-pub use self::context::Context;
+pub use self::compile::*;
+pub use self::context::{Context, ContextBuilder};
+pub use self::convert::*;
+pub use self::error::LispError;
 pub use self::expression::*;
+pub use self::gc::{GcStats, Trace};
 pub use self::keyword::*;
+pub use self::limits::InterruptHandle;
+pub use self::loader::{FileSystemLoader, MemoryLoader, ModuleLoader};
 pub use self::literal::*;
 pub use self::object::*;
+pub use self::parse::{Position, Span};
+pub use self::printer::{Repr, Text};
+pub use self::scope::*;
+pub use self::vector::{Vector, VectorNode};
 
 type Wrap<T> = Rc<T>;
 fn wrap<T>(inner: T) -> Wrap<T> {
@@ -22,28 +43,35 @@ fn wrap<T>(inner: T) -> Wrap<T> {
 #[derive(Debug)]
 pub struct IdentIntern {
     idents: HashMap<String, usize>,
+    names: Vec<String>,
 }
 
 impl IdentIntern {
     pub fn new() -> Self {
         IdentIntern {
             idents: HashMap::new(),
+            names: Vec::new(),
         }
     }
 
     pub fn get_or_add<T: Into<String>>(&mut self, ident: T) -> Ident {
-        let next = self.idents.len();
-        let val = self.idents.entry(ident.into()).or_insert(next);
-        Ident(*val)
+        let ident = ident.into();
+        if let Some(val) = self.idents.get(&ident) {
+            return Ident(*val);
+        }
+
+        let next = self.names.len();
+        self.names.push(ident.clone());
+        self.idents.insert(ident, next);
+        Ident(next)
+    }
+
+    pub fn get(&self, ident: &str) -> Option<Ident> {
+        self.idents.get(ident).map(|val| Ident(*val))
     }
 
     pub fn get_name(&self, ident: Ident) -> Option<&str> {
-        for (k, v) in &self.idents {
-            if *v == ident.0 {
-                return Some(k)
-            }
-        }
-        None
+        self.names.get(ident.0).map(|name| name.as_str())
     }
 }
 
@@ -67,17 +95,36 @@ impl NativeModuleDescription {
         }
     }
 
-    pub fn register_function<T: Into<String>>(&mut self, name: T, ptr: fn(&mut Context, Vec<Object>) -> Object) {
+    pub fn register_function<T, F>(&mut self, name: T, func: F)
+    where
+        T: Into<String>,
+        F: Fn(&mut Context, Vec<Object>) -> Object + 'static,
+    {
         self.funcs.push(NativeModuleFunction {
             name: name.into(),
-            ptr
+            func: Rc::new(func),
+        });
+    }
+
+    /// Registers a plain Rust function, converting its arguments and return
+    /// value and raising an exception when the arguments do not fit.
+    pub fn register_typed_function<T, F, Args>(&mut self, name: T, func: F)
+    where
+        T: Into<String>,
+        F: IntoNativeFunction<Args>,
+    {
+        let name = name.into();
+        let native = func.into_native_function(name.clone());
+        self.funcs.push(NativeModuleFunction {
+            name,
+            func: native.func,
         });
     }
 }
 
 pub struct NativeModuleFunction {
     name: String,
-    ptr: fn(&mut Context, Vec<Object>) -> Object,
+    func: Rc<dyn Fn(&mut Context, Vec<Object>) -> Object>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -1,3 +1,8 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Weak;
+
 use super::*;
 
 #[derive(Debug, Clone)]
@@ -6,7 +11,8 @@ pub enum Object {
     Boolean(bool),
     Number(Number),
     String(Wrap<String>),
-    List(Wrap<Vec<Object>>),
+    List(Wrap<Vector<Object>>),
+    Map(Wrap<HashMap<MapKey, Object>>),
     Function(Wrap<Function>),
     NativeFunction(NativeFunction),
     NativeObject(Wrap<::std::any::Any>),
@@ -19,31 +25,155 @@ impl Object {
         Object::NativeObject(wrap(obj))
     }
 
-    pub fn call(self, ctx: &mut Context, args: Vec<Object>) -> Object {
-        match self {
-            Object::Function(ref func) => {
-                let args: Vec<_> = args.into_iter().map(|a| a.eval(ctx)).collect();
+    /// Creates a native object holding other values, which the garbage
+    /// collector looks into to free cycles running through it.
+    pub fn create_traced_object<T: Trace + ::std::any::Any + 'static>(obj: T) -> Object {
+        let obj = wrap(obj);
+        gc::track_native(&obj);
+        Object::NativeObject(obj)
+    }
+
+    /// Displays the value as source that evaluates back to an equal value.
+    pub fn repr<'a>(&'a self, ctx: &'a Context) -> Repr<'a, Object> {
+        Repr::new(self, ctx)
+    }
+
+    /// Displays the value the way `string` and `print` show it.
+    pub fn text<'a>(&'a self, ctx: &'a Context) -> Text<'a> {
+        Text::new(self, ctx)
+    }
+
+    /// Bytes held directly by a value nothing else refers to yet, which is
+    /// what a native function allocated when it returns one.
+    pub fn allocation_size(&self) -> usize {
+        use std::mem::{size_of, size_of_val};
+        match *self {
+            Object::String(ref val) if Wrap::strong_count(val) == 1 => val.len(),
+            Object::List(ref val) if Wrap::strong_count(val) == 1 => val.len() * size_of::<Object>(),
+            Object::Map(ref val) if Wrap::strong_count(val) == 1 => val.len() * size_of::<(MapKey, Object)>(),
+            Object::NativeObject(ref val) if Wrap::strong_count(val) == 1 => size_of_val(&**val),
+            Object::Quote(ref val) if Wrap::strong_count(&val.inner) == 1 => token_size(&val.inner),
+            _ => 0,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Object::Nil => "nil",
+            Object::Boolean(_) => "boolean",
+            Object::Number(_) => "number",
+            Object::String(_) => "string",
+            Object::List(_) => "list",
+            Object::Map(_) => "map",
+            Object::Function(_) => "function",
+            Object::NativeFunction(_) => "nativefunction",
+            Object::NativeObject(_) => "nativeobject",
+            Object::Quote(_) => "quote",
+            Object::Exception(_) => "exception",
+        }
+    }
+
+    /// Orders any two values. Numbers compare exactly by value whatever their
+    /// representation, with NaN after every other number. Lists, maps and
+    /// quoted code compare element by element, values of different types are
+    /// ordered by type, and functions, native objects and exceptions are only
+    /// equal to themselves.
+    pub fn compare(&self, other: &Object) -> Ordering {
+        use self::Object::*;
+        match (self, other) {
+            (&Nil, &Nil) => Ordering::Equal,
+            (&Boolean(l), &Boolean(r)) => l.cmp(&r),
+            (&Number(l), &Number(r)) => compare_numbers(l, r),
+            (&String(ref l), &String(ref r)) => l.cmp(r),
+            (&List(ref l), &List(ref r)) => {
+                l.iter()
+                    .zip(r.iter())
+                    .map(|(l, r)| l.compare(r))
+                    .find(|o| *o != Ordering::Equal)
+                    .unwrap_or_else(|| l.len().cmp(&r.len()))
+            },
+            (&Map(ref l), &Map(ref r)) => {
+                let (l, r) = (sorted_entries(l), sorted_entries(r));
+                l.iter()
+                    .zip(r.iter())
+                    .map(|(l, r)| l.0.to_object().compare(&r.0.to_object()).then_with(|| l.1.compare(r.1)))
+                    .find(|o| *o != Ordering::Equal)
+                    .unwrap_or_else(|| l.len().cmp(&r.len()))
+            },
+            (&Quote(ref l), &Quote(ref r)) => compare_tokens(l.token(), r.token()),
+            (l, r) if l.type_rank() == r.type_rank() => l.address().cmp(&r.address()),
+            (l, r) => l.type_rank().cmp(&r.type_rank()),
+        }
+    }
 
-                ctx.create_scope();
+    /// Whether two values are the same value in memory, rather than merely
+    /// equal. Values held inline are identical when they are equal.
+    pub fn identical(&self, other: &Object) -> bool {
+        use self::Object::*;
+        match (self, other) {
+            (&Nil, &Nil) => true,
+            (&Boolean(l), &Boolean(r)) => l == r,
+            (&Number(super::Number::Integer(l)), &Number(super::Number::Integer(r))) => l == r,
+            (&Number(super::Number::Float(l)), &Number(super::Number::Float(r))) => l.to_bits() == r.to_bits(),
+            (l, r) => l.type_rank() == r.type_rank() && l.address().is_some() && l.address() == r.address(),
+        }
+    }
 
-                func.args.iter().zip(args).for_each(|(ident, value)| {
-                    ctx.declare_ident(*ident, value)
-                });
+    fn type_rank(&self) -> u8 {
+        use self::Object::*;
+        match *self {
+            Nil => 0,
+            Boolean(_) => 1,
+            Number(_) => 2,
+            String(_) => 3,
+            List(_) => 4,
+            Map(_) => 5,
+            Function(_) => 6,
+            NativeFunction(_) => 7,
+            NativeObject(_) => 8,
+            Quote(_) => 9,
+            Exception(_) => 10,
+        }
+    }
 
-                let return_val = func.body.eval(ctx);
-                ctx.drop_scope();
+    fn address(&self) -> Option<usize> {
+        use self::Object::*;
+        match *self {
+            String(ref v) => Some(Wrap::as_ptr(v) as usize),
+            List(ref v) => Some(Wrap::as_ptr(v) as usize),
+            Map(ref v) => Some(Wrap::as_ptr(v) as usize),
+            Function(ref v) => Some(Wrap::as_ptr(v) as usize),
+            NativeFunction(ref v) => Some(Wrap::as_ptr(&v.func) as *const u8 as usize),
+            NativeObject(ref v) => Some(Wrap::as_ptr(v) as *const u8 as usize),
+            Quote(ref v) => Some(Wrap::as_ptr(v) as usize),
+            Exception(ref v) => Some(Wrap::as_ptr(v) as usize),
+            Nil | Boolean(_) | Number(_) => None,
+        }
+    }
+
+    pub fn call(self, ctx: &mut Context, args: Vec<Object>) -> Object {
+        if ctx.has_exception() {
+            return Object::Nil;
+        }
 
-                return_val
+        match self {
+            Object::Function(ref func) => {
+                let args: Vec<_> = args.into_iter().map(|a| a.eval(ctx)).collect();
+                vm::call_function(ctx, func, args)
             },
             Object::NativeFunction(ref func) => {
                 let args: Vec<_> = args.into_iter().map(|a| a.eval(ctx)).collect();
-                (func.ptr)(ctx, args)
+                let result = (func.func)(ctx, args);
+                match ctx.track_allocation(&result) {
+                    Ok(()) => result,
+                    Err(exception) => ctx.raise(exception),
+                }
             },
             Object::Quote(ref quote) => {
                 let val = if quote.shelled {
                     quote.unshell().into()
                 } else {
-                    quote.inner.eval(ctx)
+                    quote.eval_inner(ctx)
                 };
 
                 args
@@ -51,11 +181,7 @@ impl Object {
                     .fold(val, |_acc, arg| arg.eval(ctx))
             },
             Object::Exception(ref excep) => {
-                eprintln!("Exception:\n{}", excep.message);
-
-                args
-                    .into_iter()
-                    .fold(Object::Nil, |_acc, arg| arg.eval(ctx))
+                ctx.raise((**excep).clone())
             },
             _ => {
                 args
@@ -65,24 +191,101 @@ impl Object {
         }
     }
 
+    /// Like `call`, but hands back an exception raised by the call instead of
+    /// reporting it.
+    pub fn try_call(self, ctx: &mut Context, args: Vec<Object>) -> Result<Object, LispError> {
+        ctx.catch(|ctx| self.call(ctx, args)).map_err(LispError::from)
+    }
+
     pub fn eval(self, ctx: &mut Context) -> Object {
         match self {
             Object::Quote(ref quote) => {
                 if quote.shelled {
                     quote.unshell().into()
                 } else {
-                    quote.inner.eval(ctx)
+                    quote.eval_inner(ctx)
                 }
             },
-            Object::Exception(ref excep) => {
-                eprintln!("Exception:\n{}", excep.message);
-                Object::Nil
-            },
             _ => self,
         }
     }
 }
 
+fn token_size(token: &Token) -> usize {
+    let children = match *token {
+        Token::Expression(ref exp) => exp.tokens.iter().map(token_size).sum(),
+        _ => 0,
+    };
+    ::std::mem::size_of::<Token>() + children
+}
+
+fn compare_numbers(l: Number, r: Number) -> Ordering {
+    match (l, r) {
+        (Number::Integer(l), Number::Integer(r)) => l.cmp(&r),
+        (Number::Float(l), Number::Float(r)) => match (l.is_nan(), r.is_nan()) {
+            (false, false) => l.partial_cmp(&r).unwrap_or(Ordering::Equal),
+            (l, r) => l.cmp(&r),
+        },
+        (Number::Integer(l), Number::Float(r)) => compare_integer_float(l, r),
+        (Number::Float(l), Number::Integer(r)) => compare_integer_float(r, l).reverse(),
+    }
+}
+
+// Converting either side would round large integers or fractions, so the
+// float is split into its whole part, compared as an integer, and fraction.
+fn compare_integer_float(l: i64, r: f64) -> Ordering {
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if r.is_nan() || r >= LIMIT {
+        return Ordering::Less;
+    }
+    if r < -LIMIT {
+        return Ordering::Greater;
+    }
+
+    let whole = r.trunc();
+    l.cmp(&(whole as i64)).then_with(|| 0.0.partial_cmp(&(r - whole)).unwrap_or(Ordering::Equal))
+}
+
+// Quoted code compares by what it says, ignoring where it was written.
+// Identifiers order by when they were first read.
+fn compare_tokens(l: &Token, r: &Token) -> Ordering {
+    fn rank(token: &Token) -> u8 {
+        match *token {
+            Token::Literal(_) => 0,
+            Token::Ident(_) => 1,
+            Token::Keyword(_) => 2,
+            Token::Expression(_) => 3,
+        }
+    }
+
+    match (l, r) {
+        (&Token::Literal(ref l), &Token::Literal(ref r)) => Object::from(l).compare(&Object::from(r)),
+        (&Token::Ident(l), &Token::Ident(r)) => l.0.cmp(&r.0),
+        (&Token::Keyword(ref l), &Token::Keyword(ref r)) => l.cmp(r),
+        (&Token::Expression(ref l), &Token::Expression(ref r)) => {
+            l.tokens
+                .iter()
+                .zip(r.tokens.iter())
+                .map(|(l, r)| compare_tokens(l, r))
+                .find(|o| *o != Ordering::Equal)
+                .unwrap_or_else(|| l.tokens.len().cmp(&r.tokens.len()))
+        },
+        (l, r) => rank(l).cmp(&rank(r)),
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Object) -> bool {
+        self.compare(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for Object {
+    fn partial_cmp(&self, other: &Object) -> Option<Ordering> {
+        Some(self.compare(other))
+    }
+}
+
 impl<'a> From<&'a Literal> for Object {
     fn from(other: &'a Literal) -> Self {
         match *other {
@@ -142,6 +345,18 @@ impl<T: Into<Object>> From<Vec<T>> for Object {
     }
 }
 
+impl From<Vector<Object>> for Object {
+    fn from(other: Vector<Object>) -> Self {
+        Object::List(wrap(other))
+    }
+}
+
+impl From<HashMap<MapKey, Object>> for Object {
+    fn from(other: HashMap<MapKey, Object>) -> Self {
+        Object::Map(wrap(other))
+    }
+}
+
 impl From<Exception> for Object {
     fn from(other: Exception) -> Self {
         Object::Exception(wrap(other))
@@ -172,24 +387,99 @@ impl ::std::fmt::Display for Object {
                 let strings: Vec<_> = value.iter().map(|i| i.to_string()).collect();
                 write!(f, "List({})", strings.join(" "))
             },
+            Map(ref value) => {
+                let strings: Vec<_> = sorted_entries(value)
+                    .into_iter()
+                    .map(|(k, v)| format!("{} {}", k.to_object(), v))
+                    .collect();
+                write!(f, "Map{{{}}}", strings.join(" "))
+            },
             Function(_) => write!(f, "[function]"),
             NativeFunction(_) => write!(f, "[native code]"),
             NativeObject(_) => write!(f, "[native object]"),
             Quote(ref quote) => write!(f, "Quote({})", quote.inner),
-            Exception(_) => write!(f, "[exception]"),
+            Exception(ref excep) => write!(f, "{}: {}", excep.kind, excep.message),
         }
     }
 }
 
+/// The values that can be used as map keys. Maps are unordered, but keys sort
+/// so that printing and listing a map is repeatable.
+///
+/// Numbers key by value like they compare, so a float with no fraction is
+/// the same key as the integer it equals, and becomes that integer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(u64),
+    String(Wrap<String>),
+}
+
+impl MapKey {
+    pub fn from_object(obj: &Object) -> Option<MapKey> {
+        match *obj {
+            Object::Nil => Some(MapKey::Nil),
+            Object::Boolean(val) => Some(MapKey::Boolean(val)),
+            Object::Number(Number::Integer(val)) => Some(MapKey::Integer(val)),
+            Object::Number(Number::Float(val)) if val.fract() == 0.0 && val.abs() < 9_223_372_036_854_775_808.0 => {
+                Some(MapKey::Integer(val as i64))
+            },
+            Object::Number(Number::Float(val)) => Some(MapKey::Float(val.to_bits())),
+            Object::String(ref val) => Some(MapKey::String(val.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn to_object(&self) -> Object {
+        match *self {
+            MapKey::Nil => Object::Nil,
+            MapKey::Boolean(val) => Object::Boolean(val),
+            MapKey::Integer(val) => Object::Number(Number::Integer(val)),
+            MapKey::Float(val) => Object::Number(Number::Float(f64::from_bits(val))),
+            MapKey::String(ref val) => Object::String(val.clone()),
+        }
+    }
+}
+
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &MapKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Keys order like the values they stand for, so integer and float keys
+// interleave.
+impl Ord for MapKey {
+    fn cmp(&self, other: &MapKey) -> Ordering {
+        self.to_object().compare(&other.to_object())
+    }
+}
+
+pub fn sorted_entries(map: &HashMap<MapKey, Object>) -> Vec<(&MapKey, &Object)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
 #[derive(Debug, Clone)]
 pub struct Function {
-    pub args: Vec<Ident>,
-    pub body: Wrap<Expression>,
+    pub proto: Wrap<Prototype>,
+    pub scope: Scope,
 }
 
 #[derive(Clone)]
 pub struct NativeFunction {
-    pub ptr: fn(&mut Context, Vec<Object>) -> Object
+    pub func: Wrap<dyn Fn(&mut Context, Vec<Object>) -> Object>,
+}
+
+impl NativeFunction {
+    pub fn new<F: Fn(&mut Context, Vec<Object>) -> Object + 'static>(func: F) -> Self {
+        NativeFunction {
+            func: wrap(func),
+        }
+    }
 }
 
 impl ::std::fmt::Debug for NativeFunction {
@@ -201,6 +491,7 @@ impl ::std::fmt::Debug for NativeFunction {
 #[derive(Debug, Clone)]
 pub struct Quote {
     inner: Wrap<Token>,
+    compiled: Wrap<RefCell<Option<Wrap<Chunk>>>>,
     shelled: bool,
 }
 
@@ -208,13 +499,23 @@ impl Quote {
     pub fn new(exp: Token) -> Self {
         Quote {
             inner: wrap(exp),
+            compiled: wrap(RefCell::new(None)),
             shelled: true,
         }
     }
 
+    pub fn token(&self) -> &Token {
+        &self.inner
+    }
+
+    pub fn downgrade(&self) -> Weak<Token> {
+        Wrap::downgrade(&self.inner)
+    }
+
     pub fn unshell(&self) -> Self {
         Quote {
             inner: self.inner.clone(),
+            compiled: self.compiled.clone(),
             shelled: false
         }
     }
@@ -222,20 +523,149 @@ impl Quote {
     pub fn reshell(&self) -> Self {
         Quote {
             inner: self.inner.clone(),
+            compiled: self.compiled.clone(),
             shelled: true
         }
     }
+
+    // Quoted code can be evaluated from any scope, so it is compiled once
+    // without resolving identifiers to slots.
+    pub fn eval_inner(&self, ctx: &mut Context) -> Object {
+        if ctx.has_exception() {
+            return Object::Nil;
+        }
+
+        let compiled = self.compiled.borrow().clone();
+        let chunk = match compiled {
+            Some(chunk) => chunk,
+            None => {
+                let token = match ctx.macroexpand(&self.inner) {
+                    Ok(token) => token,
+                    Err(exception) => return ctx.raise(exception),
+                };
+                let chunk = wrap(Compiler::compile_token(&token, None));
+                *self.compiled.borrow_mut() = Some(chunk.clone());
+                chunk
+            },
+        };
+
+        vm::run(ctx, chunk)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Exception {
+    kind: Wrap<String>,
     message: Wrap<String>,
+    payload: Object,
+    trace: Vec<String>,
 }
 
 impl Exception {
-    pub fn message<T: Into<String>>(message: T) -> Self {
+    pub fn new<K: Into<String>, M: Into<String>>(kind: K, message: M) -> Self {
         Exception {
-            message: wrap(message.into())
+            kind: wrap(kind.into()),
+            message: wrap(message.into()),
+            payload: Object::Nil,
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn message<T: Into<String>>(message: T) -> Self {
+        Self::new("error", message)
+    }
+
+    pub fn with_payload(mut self, payload: Object) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn get_kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn get_payload(&self) -> &Object {
+        &self.payload
+    }
+
+    pub fn get_trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    pub fn push_trace<T: Into<String>>(&mut self, entry: T) {
+        self.trace.push(entry.into());
+    }
+}
+
+impl ::std::fmt::Display for Exception {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)?;
+        for entry in &self.trace {
+            write!(f, "\n    at {}", entry)?;
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A module is a block, so each source starts with a value rather than a
+    // function
+    fn eval(source: &str) -> Object {
+        Context::new().eval_module(format!("(let result nil) {}", source))
+    }
+
+    #[test]
+    fn numbers_compare_exactly_across_types() {
+        assert_eq!(Object::from(1), Object::from(1.0));
+        assert_eq!(Object::from(1).compare(&Object::from(1.5)), Ordering::Less);
+        assert_eq!(Object::from(-1).compare(&Object::from(-1.5)), Ordering::Greater);
+        assert_eq!(Object::from(2.5).compare(&Object::from(2)), Ordering::Greater);
+        assert_eq!(Object::from((1 << 53) + 1).compare(&Object::from((1u64 << 53) as f64)), Ordering::Greater);
+        assert_eq!(Object::from(i64::MAX).compare(&Object::from(i64::MAX as f64)), Ordering::Less);
+        assert_eq!(Object::from(i64::MIN).compare(&Object::from(i64::MIN as f64)), Ordering::Equal);
+        assert_eq!(Object::from(i64::MAX).compare(&Object::from(::std::f64::NAN)), Ordering::Less);
+        assert_eq!(Object::from(::std::f64::INFINITY).compare(&Object::from(::std::f64::NAN)), Ordering::Less);
+        assert_eq!(Object::from(::std::f64::NAN), Object::from(::std::f64::NAN));
+    }
+
+    #[test]
+    fn eq_follows_ieee_for_nan() {
+        assert_eq!(eval("(let nan (div 0.0 0.0)) (list (= nan nan) (= nan 1) (= 1.5 1.5))"), vec![false, false, true].into());
+        assert_eq!(eval("(get {(div 0.0 0.0) \"a\"} (div 0.0 0.0))"), Object::from("a"));
+    }
+
+    #[test]
+    fn ordering_builtins_accept_mixed_numbers() {
+        let expected: Object = vec![true, true, false, false].into();
+        assert_eq!(eval("(list (> 2 1.5) (< 1 1.5) (> 1.5 2) (< 2.0 2))"), expected);
+        assert_eq!(eval("(try (> 1 \"1\") (catch e (exception_type e)))"), Object::from("type"));
+    }
+
+    #[test]
+    fn numeric_map_keys_match_equal_numbers() {
+        assert_eq!(MapKey::from_object(&Object::from(1.0)), MapKey::from_object(&Object::from(1)));
+        assert_ne!(MapKey::from_object(&Object::from(1.5)), MapKey::from_object(&Object::from(1)));
+        assert_eq!(eval("(get {1.0 \"a\"} 1)"), Object::from("a"));
+        assert_eq!(eval("(get {1 \"a\"} 1.0)"), Object::from("a"));
+        assert_eq!(eval("(= {1 \"a\"} {1.0 \"a\"})"), Object::from(true));
+        assert_eq!(eval("(= {1 \"a\"} {1.5 \"a\"})"), Object::from(false));
+        let expected: Object = vec![Object::from(1), Object::from(1.5), Object::from(2)].into();
+        assert_eq!(eval("(keys {2 \"c\" 1.5 \"b\" 1.0 \"a\"})"), expected);
+    }
+
+    #[test]
+    fn quotes_compare_structurally() {
+        assert_eq!(eval("(= (quote (a (b 1) \"c\")) (quote (a (b 1) \"c\")))"), Object::from(true));
+        assert_eq!(eval("(= (quote (a (b 1))) (quote (a (b 2))))"), Object::from(false));
+        assert_eq!(eval("(= (quote (a b)) (quote (a b c)))"), Object::from(false));
+        assert_eq!(eval("(identical (quote (a b)) (quote (a b)))"), Object::from(false));
     }
 }
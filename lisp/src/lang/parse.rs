@@ -19,30 +19,21 @@ pub enum Error {
 }
 
 impl Error {
-    pub fn into_exception<T: AsRef<str>>(self, module: T) -> Exception {
-        let module = module.as_ref();
-        let msg = match self {
-            Error::Internal(location, message) => {
-                let location = location
-                    .map(|l| format!(":{}:{} -> {}:{}", l.start.line, l.start.column, l.end.line, l.end.column))
-                    .unwrap_or("".to_string());
-                format!("Internal Error: {} \n{}{}", message, module, location)
+    pub fn into_lisp_error<T: Into<String>>(self, module: T) -> LispError {
+        let module = module.into();
+        match self {
+            Error::Internal(location, message) | Error::Expression(location, message) => LispError::Parse {
+                module,
+                position: location.map(|l| l.start),
+                message,
             },
-            Error::Expression(location, message) => {
-                let location = location
-                    .map(|l| format!(":{}:{} -> {}:{}", l.start.line, l.start.column, l.end.line, l.end.column))
-                    .unwrap_or("".to_string());
-                format!("Expression Error: {}\n{}{}", message, module, location)
+            Error::Parse(position, message) => LispError::Parse {
+                module,
+                position: Some(position),
+                message,
             },
-            Error::Parse(location, message) => {
-                format!("Parse Error: {}\n{}:{}:{}", message, module, location.line, location.column)
-            },
-            Error::IoError(err) => {
-                format!("IO Error: {}\n{}", err, module)
-            }
-        };
-
-        Exception::message(msg)
+            Error::IoError(error) => LispError::Io { path: module, error },
+        }
     }
 }
 
@@ -61,11 +52,12 @@ impl From<Expression> for TokenOrExpression {
 struct ExpressionBuilder<'a> {
     tokens: Vec<TokenOrExpression>,
     interner: &'a mut IdentIntern,
+    module: Wrap<String>,
 }
 
 impl<'a> ExpressionBuilder<'a> {
-    fn new(interner: &'a mut IdentIntern) -> Self {
-        Self { tokens: Vec::new(), interner }
+    fn new(interner: &'a mut IdentIntern, module: Wrap<String>) -> Self {
+        Self { tokens: Vec::new(), interner, module }
     }
 
     fn push(&mut self, token: TextToken, debug_info: Span) -> Result<(), Error> {
@@ -73,54 +65,101 @@ impl<'a> ExpressionBuilder<'a> {
         match token {
             TT::Comment => (),
             TT::Whitespace => (),
-            TT::OpenBrace => (),
-            TT::CloseBrace => (),
-            TT::CloseParen => {
-                let mut tokens = Vec::new();
-                loop {
-                    match self.tokens.pop() {
-                        Some(TokenOrExpression::Token(TT::OpenParen, _)) => break,
-                        Some(t_or_e) => tokens.push(t_or_e),
-                        None => {
-                            return Err(Error::Expression(Some(debug_info), "Unmatched close paren".into()));
-                        }
-                    }
-                }
-                let mut expression = Expression::new();
-                loop {
-                    let next_token = match tokens.pop() {
-                        Some(TokenOrExpression::Token(t, debug)) => match t {
-                            TT::Identifier(name) => self.interner.get_or_add(name).into(),
-                            TT::Keyword(key) => key.into(),
-                            TT::Literal(lit) => lit.into(),
-                            TT::Symbol(sym) => self.interner.get_or_add(sym.name()).into(),
-                            _ => {
-                                return Err(Error::Internal(Some(debug), "Unexpected token".into()));
-                            }
-                        },
-                        Some(TokenOrExpression::Expression(e)) => {
-                            e.into()
-                        },
-                        None => break,
-                    };
-                    expression.push(next_token);
-                }
-
-                self.tokens.push(expression.into());
+            TT::CloseParen => self.close(false, debug_info)?,
+            TT::CloseBrace => self.close(true, debug_info)?,
+            TT::OpenParen | TT::OpenBrace | TT::Prefix(_) => {
+                self.tokens.push(TokenOrExpression::Token(token, debug_info));
             },
             _ => {
                 self.tokens.push(TokenOrExpression::Token(token, debug_info));
+                self.wrap_prefixes()?;
             },
         }
 
         Ok(())
     }
 
+    // Braces read as a map literal, so `{a 1}` is the expression `(map_literal a 1)`.
+    fn close(&mut self, brace: bool, debug_info: Span) -> Result<(), Error> {
+        use self::TextToken as TT;
+        let mut tokens = Vec::new();
+        let start = loop {
+            match (self.tokens.pop(), brace) {
+                (Some(TokenOrExpression::Token(TT::OpenParen, open)), false) |
+                (Some(TokenOrExpression::Token(TT::OpenBrace, open)), true) => break open.start,
+                (Some(TokenOrExpression::Token(TT::OpenParen, _)), true) |
+                (None, true) => {
+                    return Err(Error::Expression(Some(debug_info), "Unmatched close brace".into()));
+                },
+                (Some(TokenOrExpression::Token(TT::OpenBrace, _)), false) |
+                (None, false) => {
+                    return Err(Error::Expression(Some(debug_info), "Unmatched close paren".into()));
+                },
+                (Some(t_or_e), _) => tokens.push(t_or_e),
+            }
+        };
+        if brace && tokens.len() % 2 != 0 {
+            return Err(Error::Expression(Some(debug_info), "Map literal needs a value for every key".into()));
+        }
+
+        let span = Span { start, end: debug_info.end };
+        let mut expression = Expression::with_location(Location::new(self.module.clone(), span.clone()));
+        if brace {
+            expression.push_with_span(Keyword::MapLiteral.into(), span);
+        }
+        while let Some(t_or_e) = tokens.pop() {
+            let (token, span) = self.into_token(t_or_e)?;
+            expression.push_with_span(token, span);
+        }
+
+        self.tokens.push(expression.into());
+        self.wrap_prefixes()
+    }
+
+    fn into_token(&mut self, t_or_e: TokenOrExpression) -> Result<(Token, Span), Error> {
+        use self::TextToken as TT;
+        match t_or_e {
+            TokenOrExpression::Token(t, debug) => match t {
+                TT::Identifier(name) => Ok((self.interner.get_or_add(name).into(), debug)),
+                TT::Keyword(key) => Ok((key.into(), debug)),
+                TT::Literal(lit) => Ok((lit.into(), debug)),
+                TT::Symbol(sym) => Ok((self.interner.get_or_add(sym.name()).into(), debug)),
+                _ => Err(Error::Internal(Some(debug), "Unexpected token".into())),
+            },
+            TokenOrExpression::Expression(e) => {
+                let span = e.location.as_ref().map(|l| l.span.clone()).unwrap_or_else(Span::start);
+                Ok((e.into(), span))
+            },
+        }
+    }
+
+    // Reader prefixes apply to the item that follows them, so `,x` reads as
+    // `(unquote x)`.
+    fn wrap_prefixes(&mut self) -> Result<(), Error> {
+        loop {
+            let len = self.tokens.len();
+            let (keyword, prefix_span) = match self.tokens.get(len.wrapping_sub(2)) {
+                Some(&TokenOrExpression::Token(TextToken::Prefix(ref keyword), ref span)) => (keyword.clone(), span.clone()),
+                _ => return Ok(()),
+            };
+            let item = self.tokens.pop().expect("prefixed item");
+            self.tokens.pop();
+
+            let (token, span) = self.into_token(item)?;
+            let location = Location::new(self.module.clone(), Span { start: prefix_span.start, end: span.end });
+            let mut expression = Expression::with_location(location);
+            expression.push_with_span(keyword.into(), prefix_span);
+            expression.push_with_span(token, span);
+            self.tokens.push(expression.into());
+        }
+    }
+
     fn build(mut self) -> Result<Expression, Error> {
         if self.tokens.len() != 1 {
             for token in self.tokens {
                 match token {
                     TokenOrExpression::Token(TextToken::OpenParen, debug) => return Err(Error::Expression(Some(debug), "Unmatched open paren".into())),
+                    TokenOrExpression::Token(TextToken::OpenBrace, debug) => return Err(Error::Expression(Some(debug), "Unmatched open brace".into())),
                     _ => (),
                 }
             }
@@ -139,16 +178,29 @@ impl<'a> ExpressionBuilder<'a> {
 pub struct Module;
 
 impl Module {
-    pub fn load<R: Read>(interner: &mut IdentIntern, source: R) -> Result<Expression, Error> {
+    pub fn load<R: Read>(interner: &mut IdentIntern, name: Wrap<String>, source: R) -> Result<Expression, Error> {
         let (tokens, debug_info) = Self::tokenize(source)?;
-        Self::root_expression(tokens, debug_info, interner)
+        Self::root_expression(tokens, debug_info, interner, name)
     }
 
-    fn root_expression(tokens: Vec<TextToken>, debug_info: Vec<Span>, interner: &mut IdentIntern) -> Result<Expression, Error> {
-        let mut builder = ExpressionBuilder::new(interner);
+    fn root_expression(tokens: Vec<TextToken>, debug_info: Vec<Span>, interner: &mut IdentIntern, name: Wrap<String>) -> Result<Expression, Error> {
+        let mut builder = ExpressionBuilder::new(interner, name);
 
+        // A stray close would otherwise match the module's own open paren
+        let mut depth = 0;
         builder.push(TextToken::OpenParen, Span::start())?;
         for (token, debug_info) in tokens.into_iter().zip(debug_info) {
+            match token {
+                TextToken::OpenParen | TextToken::OpenBrace => depth += 1,
+                TextToken::CloseParen if depth == 0 => {
+                    return Err(Error::Expression(Some(debug_info), "Unmatched close paren".into()));
+                },
+                TextToken::CloseBrace if depth == 0 => {
+                    return Err(Error::Expression(Some(debug_info), "Unmatched close brace".into()));
+                },
+                TextToken::CloseParen | TextToken::CloseBrace => depth -= 1,
+                _ => (),
+            }
             builder.push(token, debug_info)?;
         }
         builder.push(TextToken::CloseParen, Span::start())?;
@@ -188,7 +240,9 @@ impl Module {
                     tokens
                         .into_iter()
                         .scan(start, |start, (token, end)| {
-                            Some((token, (*start, end)))
+                            let span = (*start, end);
+                            *start = end;
+                            Some((token, span))
                         })
                         .unzip()
                 })
@@ -198,12 +252,12 @@ impl Module {
 
 #[derive(Debug, Clone)]
 pub struct Span {
-    start: Position,
-    end: Position,
+    pub start: Position,
+    pub end: Position,
 }
 
 impl Span {
-    fn start() -> Self {
+    pub fn start() -> Self {
         Span {
             start: Position { line: 1, column: 1},
             end: Position { line: 1, column: 1},
@@ -213,8 +267,14 @@ impl Span {
 
 #[derive(Debug, Clone, Copy)]
 pub struct Position {
-    line: i32,
-    column: i32,
+    pub line: i32,
+    pub column: i32,
+}
+
+impl ::std::fmt::Display for Position {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
 }
 
 impl From<SourcePosition> for Position {
@@ -237,6 +297,7 @@ pub enum TextToken {
     OpenParen,
     CloseParen,
     Symbol(Symbol),
+    Prefix(Keyword),
     Whitespace,
 }
 
@@ -295,6 +356,10 @@ where
         parse_identifier(),
         parse_symbol(),
         token('\'').map(|_| TextToken::Keyword(Keyword::Quote)),
+        try(string("&rest")).map(|_| TextToken::Keyword(Keyword::Rest)),
+        token('`').map(|_| TextToken::Prefix(Keyword::Quasiquote)),
+        try(string(",@")).map(|_| TextToken::Prefix(Keyword::UnquoteSplicing)),
+        token(',').map(|_| TextToken::Prefix(Keyword::Unquote)),
         token('(').map(|_| TextToken::OpenParen),
         token(')').map(|_| TextToken::CloseParen),
         token('{').map(|_| TextToken::OpenBrace),
@@ -308,11 +373,19 @@ where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
+    // `alias/name` reads as a single qualified identifier
     choice((letter(), token('_'))).then(|c| {
-        many(choice((letter(), digit(), token('_')))).map(move |s: String| {
+        (
+            many(choice((letter(), digit(), token('_')))),
+            optional(try(token('/').with(many1(choice((letter(), digit(), token('_'))))))),
+        ).map(move |(s, member): (String, Option<String>)| {
             let mut ident = String::with_capacity(s.len() + 1);
             ident.push(c);
             ident.push_str(&s);
+            if let Some(member) = member {
+                ident.push('/');
+                ident.push_str(&member);
+            }
             ident
         })
     }).map(|ident| match &*ident {
@@ -327,6 +400,15 @@ where
         "set" => Keyword::Set.into(),
         "loop" => Keyword::Loop.into(),
         "quote" => Keyword::Quote.into(),
+        "try" => Keyword::Try.into(),
+        "catch" => Keyword::Catch.into(),
+        "finally" => Keyword::Finally.into(),
+        "defmacro" => Keyword::Defmacro.into(),
+        "export" => Keyword::Export.into(),
+        "as" => Keyword::As.into(),
+        "quasiquote" => Keyword::Quasiquote.into(),
+        "unquote" => Keyword::Unquote.into(),
+        "unquote_splicing" => Keyword::UnquoteSplicing.into(),
         _ => TextToken::Identifier(ident)
     })
 }
@@ -370,20 +452,12 @@ where
                     _ => 1.0,
                 };
 
-                many(digit()).then(move |whole: Vec<_>| {
-                    let whole = whole.iter().fold(0.0, |acc, next| {
-                        let next = (*next as u32 - 48) as f64;
-                        acc * 10.0 + next
-                    });
-                    token('.').then(move |_| {
-                        many1(digit()).map(move |fraction: Vec<_>| {
-                            let fraction = fraction.iter().rev().fold(0.0, |acc, next| {
-                                let next = (*next as u32 - 48) as f64;
-                                (next + acc) / 10.0
-                            });
-
-                            (whole + fraction) * sign
-                        })
+                // Read through the standard library so every float printed
+                // reads back exactly
+                many(digit()).then(move |whole: String| {
+                    token('.').with(many1(digit())).map(move |fraction: String| {
+                        let value: f64 = format!("{}.{}", whole, fraction).parse().unwrap_or(0.0);
+                        value * sign
                     })
                 })
             }).map(Number::Float)),
@@ -395,12 +469,12 @@ where
                 };
 
                 many1(digit()).map(move |digits: Vec<char>| {
-                    let val = digits.iter().fold(0, |acc, next| {
+                    // The sign goes on each digit so the most negative integer
+                    // reads without overflowing
+                    digits.iter().fold(0i64, |acc, next| {
                         let next = *next as i64 - 48;
-                        acc * 10 + next
-                    });
-
-                    val * sign
+                        acc.wrapping_mul(10).wrapping_add(next * sign)
+                    })
                 })
             }).map(Number::Integer))
     )).map(Literal::Number)
@@ -425,3 +499,43 @@ where
     )).map(TextToken::Symbol)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn parse(source: &str) -> Expression {
+        let mut interner = IdentIntern::new();
+        Module::load(&mut interner, wrap("test.lisp".to_string()), Cursor::new(source)).expect("parses")
+    }
+
+    fn expression(token: &Token) -> &Expression {
+        match *token {
+            Token::Expression(ref exp) => exp,
+            ref other => panic!("expected an expression, got {}", other),
+        }
+    }
+
+    #[test]
+    fn expressions_keep_the_span_of_their_parens() {
+        let root = parse("(a\n  (b   c))");
+        let outer = expression(&root.tokens[0]);
+        let location = outer.location.as_ref().expect("outer location");
+        assert_eq!(location.to_string(), "test.lisp:1:1");
+        assert_eq!(location.span.end.to_string(), "2:11");
+
+        let inner = expression(&outer.tokens[1]);
+        assert_eq!(inner.location.as_ref().expect("inner location").to_string(), "test.lisp:2:3");
+        assert_eq!(outer.spans[1].start.to_string(), "2:3");
+    }
+
+    #[test]
+    fn tokens_keep_their_own_span() {
+        let root = parse("(a\n  (b   c))");
+        let inner = expression(&expression(&root.tokens[0]).tokens[1]);
+        assert_eq!(inner.spans[0].start.to_string(), "2:4");
+        assert_eq!(inner.spans[1].start.to_string(), "2:8");
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt::{self, Display, Formatter};
+
+use super::*;
+
+/// Prints a value as source that evaluates back to an equal value, naming
+/// identifiers through the context that interned them.
+///
+/// Lists print as calls to `list`, maps as map literals and quotes as `quote`
+/// forms. Functions, native objects and exceptions have no source form
+/// and print in brackets, which does not read back.
+pub struct Repr<'a, T: 'a> {
+    value: &'a T,
+    ctx: &'a Context,
+}
+
+impl<'a, T> Repr<'a, T> {
+    pub fn new(value: &'a T, ctx: &'a Context) -> Self {
+        Repr { value, ctx }
+    }
+
+    fn nested<U>(&self, value: &'a U) -> Repr<'a, U> {
+        Repr::new(value, self.ctx)
+    }
+}
+
+impl<'a> Display for Repr<'a, Object> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self.value {
+            Object::Nil => write!(f, "nil"),
+            Object::Boolean(value) => write!(f, "{}", value),
+            Object::Number(value) => write_number(f, value),
+            Object::String(ref value) => write_string(f, value),
+            Object::List(ref list) => {
+                write!(f, "(list")?;
+                for item in list.iter() {
+                    write!(f, " {}", self.nested(item))?;
+                }
+                write!(f, ")")
+            },
+            Object::Map(ref map) => {
+                write!(f, "{{")?;
+                for (idx, (key, value)) in sorted_entries(map).into_iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{} {}", self.nested(&key.to_object()), self.nested(value))?;
+                }
+                write!(f, "}}")
+            },
+            Object::Function(ref func) => match func.proto.chunk.name.and_then(|name| self.ctx.get_ident_name(name)) {
+                Some(name) => write!(f, "[function {}]", name),
+                None => write!(f, "[function]"),
+            },
+            Object::NativeFunction(_) => write!(f, "[native code]"),
+            Object::NativeObject(_) => write!(f, "[native object]"),
+            Object::Quote(ref quote) => write!(f, "(quote {})", self.nested(quote.token())),
+            Object::Exception(ref exception) => write!(f, "[exception {}]", exception),
+        }
+    }
+}
+
+/// Prints a value the way `string` and `print` show it: strings as they
+/// are and collections like their `Display`, but with quotes printed as
+/// source and identifiers named through the context.
+pub struct Text<'a> {
+    value: &'a Object,
+    ctx: &'a Context,
+}
+
+impl<'a> Text<'a> {
+    pub fn new(value: &'a Object, ctx: &'a Context) -> Self {
+        Text { value, ctx }
+    }
+
+    fn nested(&self, value: &'a Object) -> Text<'a> {
+        Text::new(value, self.ctx)
+    }
+}
+
+impl<'a> Display for Text<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self.value {
+            Object::List(ref list) => {
+                write!(f, "List(")?;
+                for (idx, item) in list.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", self.nested(item))?;
+                }
+                write!(f, ")")
+            },
+            Object::Map(ref map) => {
+                write!(f, "Map{{")?;
+                for (idx, (key, value)) in sorted_entries(map).into_iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{} {}", Text::new(&key.to_object(), self.ctx), self.nested(value))?;
+                }
+                write!(f, "}}")
+            },
+            Object::Quote(_) => write!(f, "{}", Repr::new(self.value, self.ctx)),
+            ref value => write!(f, "{}", value),
+        }
+    }
+}
+
+impl<'a> Display for Repr<'a, Token> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self.value {
+            Token::Ident(ident) => match self.ctx.get_ident_name(ident) {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "{}", self.value),
+            },
+            Token::Literal(Literal::Number(value)) => write_number(f, value),
+            Token::Literal(ref literal) => write!(f, "{}", literal),
+            Token::Keyword(ref keyword) => write!(f, "{}", keyword),
+            Token::Expression(ref exp) => write!(f, "{}", self.nested(exp)),
+        }
+    }
+}
+
+impl<'a> Display for Repr<'a, Expression> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (open, close, tokens) = match self.value.tokens.first() {
+            Some(&Token::Keyword(Keyword::MapLiteral)) => ("{", "}", &self.value.tokens[1..]),
+            _ => ("(", ")", &self.value.tokens[..]),
+        };
+
+        write!(f, "{}", open)?;
+        for (idx, token) in tokens.iter().enumerate() {
+            if idx > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", self.nested(token))?;
+        }
+        write!(f, "{}", close)
+    }
+}
+
+// Floats always carry a fraction so they do not read back as integers, and
+// the ones with no literal are built by division.
+fn write_number(f: &mut Formatter, value: Number) -> fmt::Result {
+    match value {
+        Number::Integer(value) => write!(f, "{}", value),
+        Number::Float(value) if value.is_nan() => write!(f, "(div 0.0 0.0)"),
+        Number::Float(value) if value.is_infinite() => {
+            write!(f, "(div {} 0.0)", if value > 0.0 { "1.0" } else { "-1.0" })
+        },
+        Number::Float(value) if value.fract() == 0.0 => write!(f, "{:.1}", value),
+        Number::Float(value) => write!(f, "{}", value),
+    }
+}
+
+/// Writes `value` as a string literal, escaping what the parser would read
+/// differently.
+pub fn write_string(f: &mut Formatter, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Object {
+        Context::new().try_eval_module(source).expect("evaluates")
+    }
+
+    #[test]
+    fn string_names_identifiers_in_quotes() {
+        assert_eq!(eval("(string (quote (foo 1 \"a\")))"), Object::from("(quote (foo 1 \"a\"))"));
+        assert_eq!(eval("(concat (list 1 (quote bar)) \"x\")"), Object::from("1(quote bar)x"));
+        assert_eq!(eval("(join (list (quote a) (quote b)) \",\")"), Object::from("(quote a),(quote b)"));
+        assert_eq!(eval("(format \"{}\" (list (quote baz)))"), Object::from("List((quote baz))"));
+    }
+
+    #[test]
+    fn repr_reads_back() {
+        let source = "(list 1 2.0 \"a \\\"b\\\"\" (quote (foo bar)) {\"k\" (list nil true)})";
+        assert_eq!(eval(&format!("(= (eval (repr {0})) {0})", source)), Object::from(true));
+    }
+}
@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Weak;
+
+use super::*;
+
+/// A single frame of variables along with the frame it was created in.
+///
+/// Variables live in numbered slots so compiled code can address them as
+/// `(depth, slot)` pairs; the slot names are kept alongside for code that
+/// can only be resolved at runtime, such as quoted code and native imports.
+#[derive(Clone)]
+pub struct Scope {
+    inner: Wrap<RefCell<ScopeFrame>>,
+}
+
+struct ScopeFrame {
+    names: Wrap<Vec<Ident>>,
+    slots: Vec<Object>,
+    parent: Option<Scope>,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self::with_layout(None, wrap(Vec::new()))
+    }
+
+    pub fn child(&self) -> Self {
+        Self::with_layout(Some(self.clone()), wrap(Vec::new()))
+    }
+
+    pub fn with_layout(parent: Option<Scope>, names: Wrap<Vec<Ident>>) -> Self {
+        let slots = vec![Object::Nil; names.len()];
+        let scope = Scope {
+            inner: wrap(RefCell::new(ScopeFrame {
+                names,
+                slots,
+                parent,
+            }))
+        };
+        gc::track_scope(&scope);
+
+        scope
+    }
+
+    pub fn address(&self) -> usize {
+        Wrap::as_ptr(&self.inner) as usize
+    }
+
+    pub fn strong_count(&self) -> usize {
+        Wrap::strong_count(&self.inner)
+    }
+
+    /// Calls `visit` with every value held in this frame, not counting its
+    /// parents.
+    pub fn trace(&self, visit: &mut dyn FnMut(&Object)) {
+        self.inner.borrow().slots.iter().for_each(visit);
+    }
+
+    /// Drops every value and the parent of this frame, breaking any cycle
+    /// running through it.
+    pub fn clear(&self) {
+        let (slots, parent) = {
+            let mut frame = self.inner.borrow_mut();
+            let slots = frame.slots.iter_mut().map(|slot| ::std::mem::replace(slot, Object::Nil)).collect::<Vec<_>>();
+            (slots, frame.parent.take())
+        };
+        drop(slots);
+        drop(parent);
+    }
+
+    pub fn parent(&self) -> Option<Scope> {
+        self.inner.borrow().parent.clone()
+    }
+
+    pub fn vars(&self) -> HashMap<Ident, Object> {
+        let frame = self.inner.borrow();
+        frame.names.iter().cloned().zip(frame.slots.iter().cloned()).collect()
+    }
+
+    /// Returns the slot for `ident` in this frame, adding an empty one if it
+    /// has not been declared yet.
+    pub fn reserve(&self, ident: Ident) -> usize {
+        let mut frame = self.inner.borrow_mut();
+        if let Some(slot) = frame.names.iter().position(|n| *n == ident) {
+            slot
+        } else {
+            ::std::rc::Rc::make_mut(&mut frame.names).push(ident);
+            frame.slots.push(Object::Nil);
+            frame.slots.len() - 1
+        }
+    }
+
+    /// Finds the `(depth, slot)` address of `ident` relative to this frame.
+    pub fn lookup(&self, ident: Ident) -> Option<(usize, usize)> {
+        let mut depth = 0;
+        let mut scope = Some(self.clone());
+        while let Some(current) = scope {
+            if let Some(slot) = current.inner.borrow().names.iter().position(|n| *n == ident) {
+                return Some((depth, slot));
+            }
+            depth += 1;
+            scope = current.parent();
+        }
+
+        None
+    }
+
+    pub fn get(&self, depth: usize, slot: usize) -> Object {
+        let frame = self.inner.borrow();
+        if depth == 0 {
+            frame.slots.get(slot).cloned().unwrap_or(Object::Nil)
+        } else {
+            frame.parent.as_ref().map(|p| p.get(depth - 1, slot)).unwrap_or(Object::Nil)
+        }
+    }
+
+    pub fn set(&self, depth: usize, slot: usize, value: Object) {
+        if depth == 0 {
+            if let Some(val) = self.inner.borrow_mut().slots.get_mut(slot) {
+                *val = value;
+            }
+        } else if let Some(ref parent) = self.inner.borrow().parent {
+            parent.set(depth - 1, slot, value);
+        }
+    }
+
+    pub fn declare(&self, ident: Ident, value: Object) {
+        let slot = self.reserve(ident);
+        self.set(0, slot, value);
+    }
+
+    pub fn assign(&self, ident: Ident, value: Object) -> bool {
+        if let Some((depth, slot)) = self.lookup(ident) {
+            self.set(depth, slot, value);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn resolve(&self, ident: Ident) -> Option<Object> {
+        self.lookup(ident).map(|(depth, slot)| self.get(depth, slot))
+    }
+
+    pub fn downgrade(&self) -> WeakScope {
+        WeakScope {
+            inner: Wrap::downgrade(&self.inner),
+        }
+    }
+
+    /// Bytes held by the slots of this frame, not counting its parents.
+    pub fn allocation_size(&self) -> usize {
+        self.inner.borrow().slots.len() * ::std::mem::size_of::<Object>()
+    }
+}
+
+/// A scope that is not kept alive by being referred to.
+#[derive(Clone)]
+pub struct WeakScope {
+    inner: Weak<RefCell<ScopeFrame>>,
+}
+
+impl WeakScope {
+    pub fn upgrade(&self) -> Option<Scope> {
+        self.inner.upgrade().map(|inner| Scope { inner })
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.inner.strong_count() > 0
+    }
+}
+
+impl ::std::fmt::Debug for Scope {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Scope({} vars)", self.inner.borrow().slots.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A module is a block, so each source starts with a value rather than a
+    // function
+    fn eval(source: &str) -> String {
+        Context::new().eval_module(source).to_string()
+    }
+
+    #[test]
+    fn closures_keep_their_defining_scope_alive() {
+        assert_eq!(eval("(let add3 nil) (func adder (a) (lambda (b) (+ a b))) (set add3 (adder 3)) (add3 4)"), "7");
+        let source = "
+            (let c nil)
+            (func counter () ((let n 0) (lambda () (set n (+ n 1)))))
+            (set c (counter))
+            (c) (c) (c)
+        ";
+        assert_eq!(eval(source), "3");
+    }
+
+    #[test]
+    fn closures_from_separate_calls_do_not_share_state() {
+        let source = "
+            (let a nil)
+            (let b nil)
+            (func counter () ((let n 0) (lambda () (set n (+ n 1)))))
+            (set a (counter))
+            (set b (counter))
+            (a) (a) (b)
+            (list (a) (b))
+        ";
+        assert_eq!(eval(source), "List(3 2)");
+    }
+
+    #[test]
+    fn names_resolve_where_the_function_was_defined() {
+        assert_eq!(eval("(let x 1) (func get () (x)) (func shadow (x) (get)) (shadow 2)"), "1");
+        assert_eq!(eval("(let x 1) (func get () (x)) (set x 5) (get)"), "5");
+    }
+}
@@ -0,0 +1,316 @@
+use std::fmt;
+use std::iter::FromIterator;
+use std::rc::Rc;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+/// A persistent vector: clones are cheap and share their items, and updating
+/// or appending to one copies only the path to the changed item, so both
+/// take O(log n) time and leave other clones untouched.
+///
+/// Items are kept in a trie of 32-wide nodes, plus a tail of up to 32 items
+/// that appends go to before it is moved into the trie. Nodes only one
+/// vector refers to are changed in place.
+#[derive(Clone)]
+pub struct Vector<T> {
+    len: usize,
+    shift: usize,
+    root: Rc<VectorNode<T>>,
+    tail: Rc<Vec<T>>,
+}
+
+/// A node of a `Vector`'s trie, exposed so the garbage collector can follow
+/// references through vectors that share nodes.
+#[derive(Clone)]
+pub enum VectorNode<T> {
+    Branch(Vec<Rc<VectorNode<T>>>),
+    Leaf(Vec<T>),
+}
+
+impl<T: Clone> Vector<T> {
+    pub fn new() -> Self {
+        Vector {
+            len: 0,
+            shift: BITS,
+            root: Rc::new(VectorNode::Branch(Vec::new())),
+            tail: Rc::new(Vec::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        if idx >= self.len {
+            return None;
+        }
+
+        Some(&self.leaf(idx)[idx & MASK])
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            vector: self,
+            idx: 0,
+            leaf: &[],
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    /// Replaces the item at `idx`, returning false if it is out of bounds.
+    pub fn set(&mut self, idx: usize, value: T) -> bool {
+        if idx >= self.len {
+            return false;
+        }
+
+        if idx >= self.tail_offset() {
+            let offset = self.tail_offset();
+            Rc::make_mut(&mut self.tail)[idx - offset] = value;
+        } else {
+            let mut node = &mut self.root;
+            let mut level = self.shift;
+            while level > 0 {
+                node = match *Rc::make_mut(node) {
+                    VectorNode::Branch(ref mut children) => &mut children[(idx >> level) & MASK],
+                    VectorNode::Leaf(_) => unreachable!("vector leaf above the bottom level"),
+                };
+                level -= BITS;
+            }
+            match *Rc::make_mut(node) {
+                VectorNode::Leaf(ref mut items) => items[idx & MASK] = value,
+                VectorNode::Branch(_) => unreachable!("vector branch at the bottom level"),
+            }
+        }
+
+        true
+    }
+
+    pub fn push(&mut self, value: T) {
+        if self.tail.len() < WIDTH {
+            Rc::make_mut(&mut self.tail).push(value);
+            self.len += 1;
+            return;
+        }
+
+        // The tail is full, so it moves into the trie, growing the trie by a
+        // level when the root has no room left
+        let tail = ::std::mem::replace(&mut self.tail, Rc::new(vec![value]));
+        let leaf = Rc::new(VectorNode::Leaf(Rc::try_unwrap(tail).unwrap_or_else(|tail| (*tail).clone())));
+        if (self.len >> BITS) > (1 << self.shift) {
+            let path = new_path(self.shift, leaf);
+            let root = ::std::mem::replace(&mut self.root, Rc::new(VectorNode::Branch(Vec::new())));
+            self.root = Rc::new(VectorNode::Branch(vec![root, path]));
+            self.shift += BITS;
+        } else {
+            push_tail(self.len, self.shift, &mut self.root, leaf);
+        }
+        self.len += 1;
+    }
+
+    pub fn root(&self) -> &Rc<VectorNode<T>> {
+        &self.root
+    }
+
+    pub fn tail(&self) -> &Rc<Vec<T>> {
+        &self.tail
+    }
+
+    fn tail_offset(&self) -> usize {
+        self.len - self.tail.len()
+    }
+
+    // The 32 items, or the tail, holding `idx`
+    fn leaf(&self, idx: usize) -> &[T] {
+        if idx >= self.tail_offset() {
+            return &self.tail;
+        }
+
+        let mut node = &*self.root;
+        let mut level = self.shift;
+        loop {
+            match *node {
+                VectorNode::Branch(ref children) => node = &children[(idx >> level) & MASK],
+                VectorNode::Leaf(ref items) => return items,
+            }
+            level -= BITS;
+        }
+    }
+}
+
+fn new_path<T>(level: usize, leaf: Rc<VectorNode<T>>) -> Rc<VectorNode<T>> {
+    if level == 0 {
+        leaf
+    } else {
+        Rc::new(VectorNode::Branch(vec![new_path(level - BITS, leaf)]))
+    }
+}
+
+// `len` counts the items before the tail moved in
+fn push_tail<T: Clone>(len: usize, level: usize, node: &mut Rc<VectorNode<T>>, leaf: Rc<VectorNode<T>>) {
+    let children = match *Rc::make_mut(node) {
+        VectorNode::Branch(ref mut children) => children,
+        VectorNode::Leaf(_) => unreachable!("vector leaf above the bottom level"),
+    };
+
+    let sub = ((len - 1) >> level) & MASK;
+    if level == BITS {
+        children.push(leaf);
+    } else if sub < children.len() {
+        push_tail(len, level - BITS, &mut children[sub], leaf);
+    } else {
+        children.push(new_path(level - BITS, leaf));
+    }
+}
+
+impl<T: Clone> Default for Vector<T> {
+    fn default() -> Self {
+        Vector::new()
+    }
+}
+
+impl<T: Clone> FromIterator<T> for Vector<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut vector = Vector::new();
+        for item in iter {
+            vector.push(item);
+        }
+
+        vector
+    }
+}
+
+impl<T: Clone> From<Vec<T>> for Vector<T> {
+    fn from(other: Vec<T>) -> Self {
+        other.into_iter().collect()
+    }
+}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for Vector<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, T: Clone> IntoIterator for &'a Vector<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    vector: &'a Vector<T>,
+    idx: usize,
+    leaf: &'a [T],
+}
+
+impl<'a, T: Clone> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.idx >= self.vector.len {
+            return None;
+        }
+        if self.idx & MASK == 0 {
+            self.leaf = self.vector.leaf(self.idx);
+        }
+
+        let item = &self.leaf[self.idx & MASK];
+        self.idx += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.vector.len - self.idx;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, T: Clone> ExactSizeIterator for Iter<'a, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lengths either side of the tail filling up at 32 items, and of the
+    // trie growing a level once 32 * 32 and 32 * 32 * 32 items sit below the
+    // tail
+    const BOUNDARIES: &[usize] = &[0, 1, 31, 32, 33, 64, 65, 1055, 1056, 1057, 1088, 1089, 32800, 32801, 32833, 33824, 33825];
+
+    fn build(len: usize) -> Vector<usize> {
+        (0..len).collect()
+    }
+
+    fn assert_holds(vector: &Vector<usize>, len: usize) {
+        assert_eq!(vector.len(), len);
+        assert!(vector.iter().cloned().eq(0..len), "items of a vector of {}", len);
+        assert!((0..len).all(|idx| vector.get(idx) == Some(&idx)), "indexing a vector of {}", len);
+        assert_eq!(vector.get(len), None);
+    }
+
+    #[test]
+    fn pushes_across_levels() {
+        let mut vector = Vector::new();
+        for len in 0..33825 {
+            assert_eq!(vector.len(), len);
+            vector.push(len);
+        }
+        for &len in BOUNDARIES {
+            assert_holds(&build(len), len);
+        }
+        assert_eq!(build(1056).shift, BITS);
+        assert_eq!(build(1057).shift, 2 * BITS);
+        assert_eq!(build(32800).shift, 2 * BITS);
+        assert_eq!(build(32801).shift, 3 * BITS);
+    }
+
+    #[test]
+    fn updates_leave_other_versions_unchanged() {
+        for &len in BOUNDARIES {
+            let original = build(len);
+            let mut updated = original.clone();
+            let mut expected: Vec<_> = (0..len).collect();
+            for idx in (0..len).step_by(7).chain(len.checked_sub(1)) {
+                assert!(updated.set(idx, idx + len));
+                expected[idx] = idx + len;
+            }
+            assert!(!updated.set(len, 0));
+
+            assert_holds(&original, len);
+            assert_eq!(updated.to_vec(), expected);
+        }
+    }
+
+    #[test]
+    fn pushes_leave_other_versions_unchanged() {
+        for &len in BOUNDARIES {
+            let original = build(len);
+            let mut longer = original.clone();
+            for idx in len..len + 40 {
+                longer.push(idx);
+            }
+            let mut sibling = original.clone();
+            sibling.push(usize::max_value());
+
+            assert_holds(&original, len);
+            assert_holds(&longer, len + 40);
+            assert_eq!(sibling.get(len), Some(&usize::max_value()));
+        }
+    }
+}
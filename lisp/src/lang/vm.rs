@@ -0,0 +1,538 @@
+use super::*;
+
+struct Frame {
+    chunk: Wrap<Chunk>,
+    pc: usize,
+    base: usize,
+    argc: usize,
+    caller_scope: Scope,
+}
+
+struct Handler {
+    frame: usize,
+    stack: usize,
+    to: usize,
+}
+
+pub fn run(ctx: &mut Context, chunk: Wrap<Chunk>) -> Object {
+    run_with_args(ctx, chunk, 0)
+}
+
+fn run_with_args(ctx: &mut Context, chunk: Wrap<Chunk>, argc: usize) -> Object {
+    if ctx.has_exception() {
+        return Object::Nil;
+    }
+
+    ctx.enter_vm();
+    let result = execute(ctx, chunk, argc);
+    ctx.exit_vm();
+
+    result
+}
+
+// Calls between Lisp functions are made by pushing a frame rather than by
+// recursing, so only native code calling back into Lisp grows the Rust stack.
+fn execute(ctx: &mut Context, chunk: Wrap<Chunk>, argc: usize) -> Object {
+    let mut stack: Vec<Object> = Vec::new();
+    let mut frames = Vec::new();
+    let mut handlers: Vec<Handler> = Vec::new();
+    let mut frame = Frame {
+        chunk,
+        pc: 0,
+        base: 0,
+        argc,
+        caller_scope: ctx.current_scope(),
+    };
+
+    loop {
+        if ctx.has_exception() && !unwind(ctx, &mut stack, &mut frame, &mut frames, &mut handlers) {
+            return Object::Nil;
+        }
+        if let Err(exception) = ctx.step() {
+            ctx.raise(exception);
+            continue;
+        }
+
+        let op = match frame.chunk.code.get(frame.pc) {
+            Some(op) => *op,
+            None => Op::Return,
+        };
+        frame.pc += 1;
+
+        match op {
+            Op::Constant(idx) => stack.push(frame.chunk.constants[idx as usize].clone()),
+            Op::LoadLocal(depth, slot) => {
+                let value = ctx.scope().get(depth as usize, slot as usize);
+                stack.push(value);
+            },
+            Op::StoreLocal(depth, slot) => {
+                let value = stack.last().cloned().unwrap_or(Object::Nil);
+                ctx.scope().set(depth as usize, slot as usize, value);
+            },
+            Op::LoadName(idx) => {
+                let value = ctx.resolve_ident(frame.chunk.names[idx as usize]);
+                stack.push(value);
+            },
+            Op::DeclareName(idx) => {
+                let value = stack.last().cloned().unwrap_or(Object::Nil);
+                ctx.declare_ident(frame.chunk.names[idx as usize], value);
+            },
+            Op::AssignName(idx) => {
+                let ident = frame.chunk.names[idx as usize];
+                let value = stack.pop().unwrap_or(Object::Nil);
+                ctx.assign_ident(ident, value);
+                stack.push(ctx.resolve_ident(ident));
+            },
+            Op::MakeFunction(idx) => {
+                let func = Function {
+                    proto: frame.chunk.functions[idx as usize].clone(),
+                    scope: ctx.current_scope(),
+                };
+                stack.push(Object::Function(wrap(func)));
+            },
+            Op::Import => {
+                let result = match stack.pop() {
+                    Some(Object::String(path)) => ctx.import_module(path.to_string()),
+                    _ => Object::Nil,
+                };
+                stack.push(result);
+            },
+            Op::ImportAll => {
+                if let Some(&Object::Map(ref namespace)) = stack.last() {
+                    for (name, value) in namespace.iter() {
+                        if let MapKey::String(ref name) = *name {
+                            let ident = ctx.get_or_add_ident(name.to_string());
+                            ctx.declare_ident(ident, value.clone());
+                        }
+                    }
+                }
+            },
+            Op::ImportName(idx) => {
+                let ident = frame.chunk.names[idx as usize];
+                let name = ctx.get_ident_name(ident).unwrap_or("").to_string();
+                let value = match stack.last() {
+                    Some(&Object::Map(ref namespace)) => namespace.get(&MapKey::String(wrap(name.clone()))).cloned(),
+                    _ => None,
+                };
+                match value {
+                    Some(value) => stack.push(value),
+                    None => {
+                        stack.push(Object::Nil);
+                        ctx.raise(Exception::new("import", format!("module does not export {}", name)));
+                    },
+                }
+            },
+            Op::Export(idx) => ctx.export(frame.chunk.names[idx as usize]),
+            Op::Call(argc) => {
+                let args = stack.split_off(stack.len() - argc as usize);
+                let callee = stack.pop().unwrap_or(Object::Nil);
+                match callee {
+                    Object::Function(func) => {
+                        let args: Vec<_> = args.into_iter().map(|a| a.eval(ctx)).collect();
+                        let argc = args.len();
+                        let scope = match function_scope(ctx, &func, args) {
+                            Ok(scope) => scope,
+                            Err(exception) => {
+                                ctx.raise(block_call_hint(&frame, exception));
+                                continue;
+                            },
+                        };
+                        let caller_scope = ctx.swap_scope(scope);
+                        let callee_frame = Frame {
+                            chunk: func.proto.chunk.clone(),
+                            pc: 0,
+                            base: stack.len(),
+                            argc,
+                            caller_scope,
+                        };
+                        frames.push(::std::mem::replace(&mut frame, callee_frame));
+                    },
+                    callee => {
+                        let result = callee.call(ctx, args);
+                        stack.push(result);
+                    },
+                }
+            },
+            Op::TailCall(argc) => {
+                let args = stack.split_off(stack.len() - argc as usize);
+                let callee = stack.pop().unwrap_or(Object::Nil);
+                match callee {
+                    Object::Function(func) => {
+                        let args: Vec<_> = args.into_iter().map(|a| a.eval(ctx)).collect();
+                        let argc = args.len();
+                        match function_scope(ctx, &func, args) {
+                            Ok(scope) => { ctx.swap_scope(scope); },
+                            Err(exception) => {
+                                ctx.raise(block_call_hint(&frame, exception));
+                                continue;
+                            },
+                        }
+                        stack.truncate(frame.base);
+                        frame.chunk = func.proto.chunk.clone();
+                        frame.pc = 0;
+                        frame.argc = argc;
+                    },
+                    callee => {
+                        let result = callee.call(ctx, args);
+                        stack.push(result);
+                        if ctx.has_exception() {
+                            continue;
+                        }
+                        if let Some(result) = return_from(ctx, &mut stack, &mut frame, &mut frames) {
+                            return result;
+                        }
+                    },
+                }
+            },
+            Op::Return => {
+                if let Some(result) = return_from(ctx, &mut stack, &mut frame, &mut frames) {
+                    return result;
+                }
+            },
+            Op::Eval => {
+                let value = stack.pop().unwrap_or(Object::Nil);
+                stack.push(value.eval(ctx));
+            },
+            Op::Jump(to) => frame.pc = to as usize,
+            Op::JumpIfFalse(to) => {
+                match stack.pop() {
+                    Some(Object::Nil) | Some(Object::Boolean(false)) | None => frame.pc = to as usize,
+                    _ => (),
+                }
+            },
+            Op::JumpIfCallable(offset, to) => {
+                let idx = stack.len() - 1 - offset as usize;
+                match stack[idx] {
+                    Object::Function(_) | Object::NativeFunction(_) |
+                    Object::Quote(_) | Object::Exception(_) => frame.pc = to as usize,
+                    _ => (),
+                }
+            },
+            Op::JumpIfSupplied(idx, to) => {
+                if frame.argc > idx as usize {
+                    frame.pc = to as usize;
+                }
+            },
+            Op::PushHandler(to) => {
+                handlers.push(Handler {
+                    frame: frames.len(),
+                    stack: stack.len(),
+                    to: to as usize,
+                });
+            },
+            Op::PopHandler => {
+                handlers.pop();
+            },
+            Op::MatchException(kind, to) => {
+                let matches = match (stack.last(), &frame.chunk.constants[kind as usize]) {
+                    (Some(&Object::Exception(ref excep)), &Object::String(ref kind)) => excep.get_kind() == &***kind,
+                    _ => false,
+                };
+                if !matches {
+                    frame.pc = to as usize;
+                }
+            },
+            Op::Throw => {
+                let exception = match stack.pop() {
+                    Some(Object::Exception(excep)) => (*excep).clone(),
+                    Some(value) => Exception::new("error", value.to_string()).with_payload(value),
+                    None => Exception::message("nil"),
+                };
+                ctx.raise(exception);
+            },
+            Op::Quasiquote(idx, count) => {
+                let values = stack.split_off(stack.len() - count as usize);
+                let filled = match frame.chunk.constants[idx as usize] {
+                    Object::Quote(ref template) => macros::fill_template(template.token(), &mut values.into_iter()),
+                    _ => Ok(Token::Literal(Literal::Nil)),
+                };
+                let filled = filled.and_then(|token| {
+                    let quote = Quote::new(token).into();
+                    ctx.track_allocation(&quote).map(|()| quote)
+                });
+                match filled {
+                    Ok(quote) => stack.push(quote),
+                    Err(exception) => {
+                        stack.push(Object::Nil);
+                        ctx.raise(exception);
+                    },
+                }
+            },
+            Op::MakeMap(count) => {
+                let entries = stack.split_off(stack.len() - 2 * count as usize);
+                let mut map = ::std::collections::HashMap::new();
+                let mut entries = entries.into_iter();
+                while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                    match MapKey::from_object(&key) {
+                        Some(key) => { map.insert(key, value); },
+                        None => {
+                            ctx.raise(Exception::new("type", format!("{} cannot be used as a map key", key.type_name())));
+                            break;
+                        },
+                    }
+                }
+                let map: Object = map.into();
+                if let Err(exception) = ctx.track_allocation(&map) {
+                    ctx.raise(exception);
+                }
+                stack.push(map);
+            },
+            Op::Pop => {
+                stack.pop();
+            },
+        }
+    }
+}
+
+// Unwinds frames until reaching the innermost handler, which resumes with the
+// pending exception on the stack. Returns false if no handler in this run of the
+// VM caught it, leaving the exception pending for the caller. Interrupts skip
+// every handler so the whole evaluation stops.
+fn unwind(ctx: &mut Context, stack: &mut Vec<Object>, frame: &mut Frame, frames: &mut Vec<Frame>, handlers: &mut Vec<Handler>) -> bool {
+    let mut exception = match ctx.take_exception() {
+        Some(exception) => exception,
+        None => return true,
+    };
+
+    loop {
+        if !ctx.is_interrupted() && handlers.last().map(|h| h.frame == frames.len()).unwrap_or(false) {
+            let handler = handlers.pop().expect("handler");
+            stack.truncate(handler.stack);
+            stack.push(exception.into());
+            frame.pc = handler.to;
+            return true;
+        }
+
+        let name = frame.chunk.name
+            .and_then(|n| ctx.get_ident_name(n))
+            .unwrap_or("<anonymous>");
+        let entry = match frame.chunk.location(frame.pc.saturating_sub(1)) {
+            Some(location) => format!("{} ({})", name, location),
+            None => name.to_string(),
+        };
+        exception.push_trace(entry);
+
+        stack.truncate(frame.base);
+        ctx.swap_scope(frame.caller_scope.clone());
+        match frames.pop() {
+            Some(caller) => *frame = caller,
+            None => {
+                ctx.raise(exception);
+                return false;
+            },
+        }
+    }
+}
+
+// Pops the current frame, handing its result to the caller. Returns the result
+// once the outermost frame has finished.
+fn return_from(ctx: &mut Context, stack: &mut Vec<Object>, frame: &mut Frame, frames: &mut Vec<Frame>) -> Option<Object> {
+    let result = stack.pop().unwrap_or(Object::Nil);
+    stack.truncate(frame.base);
+    ctx.swap_scope(frame.caller_scope.clone());
+    match frames.pop() {
+        Some(caller) => {
+            *frame = caller;
+            stack.push(result);
+            None
+        },
+        None => Some(result),
+    }
+}
+
+pub fn call_function(ctx: &mut Context, func: &Function, args: Vec<Object>) -> Object {
+    let argc = args.len();
+    let scope = match function_scope(ctx, func, args) {
+        Ok(scope) => scope,
+        Err(exception) => {
+            ctx.enter_vm();
+            ctx.raise(exception);
+            ctx.exit_vm();
+            return Object::Nil;
+        },
+    };
+    let caller_scope = ctx.swap_scope(scope);
+    let return_val = run_with_args(ctx, func.proto.chunk.clone(), argc);
+    ctx.swap_scope(caller_scope);
+
+    return_val
+}
+
+/// Builds the exception raised when `name` is called with `got` arguments but
+/// takes `required` up to `maximum` (unbounded when `None`).
+pub fn arity_exception(name: &str, required: usize, maximum: Option<usize>, got: usize) -> Exception {
+    let expected = match maximum {
+        None => format!("at least {}", required),
+        Some(maximum) if required == maximum => required.to_string(),
+        Some(maximum) => format!("{} to {}", required, maximum),
+    };
+    let noun = if expected.ends_with(" 1") || expected == "1" { "argument" } else { "arguments" };
+    Exception::new("arity", format!("{} expects {} {}, got {}", name, expected, noun, got))
+}
+
+// A block whose first expression returns a function calls it with the rest of
+// the block as arguments, which is rarely what was meant when it fails.
+fn block_call_hint(frame: &Frame, exception: Exception) -> Exception {
+    if exception.get_kind() != "arity" || !frame.chunk.is_block_call(frame.pc - 1) {
+        return exception;
+    }
+
+    Exception::new("arity", format!(
+        "{} (the first expression of this block returned a function, so the rest of the block was passed to it as arguments)",
+        exception.get_message()
+    ))
+}
+
+fn function_scope(ctx: &mut Context, func: &Function, args: Vec<Object>) -> Result<Scope, Exception> {
+    let proto = &func.proto;
+    if args.len() < proto.required || (proto.rest.is_none() && args.len() > proto.params.len()) {
+        let name = proto.chunk.name
+            .and_then(|n| ctx.get_ident_name(n))
+            .unwrap_or("<anonymous>");
+        let maximum = match proto.rest {
+            Some(_) => None,
+            None => Some(proto.params.len()),
+        };
+        return Err(arity_exception(name, proto.required, maximum, args.len()));
+    }
+
+    // Calls are where scopes pile up, and nothing is borrowed here
+    if gc::is_due() {
+        gc::collect();
+    }
+    let scope = Scope::with_layout(Some(func.scope.clone()), proto.layout.clone());
+    let mut args = args.into_iter();
+    for (slot, value) in proto.params.iter().zip(args.by_ref()) {
+        scope.set(0, *slot, value);
+    }
+    if let Some(slot) = proto.rest {
+        let rest: Vec<_> = args.collect();
+        scope.set(0, slot, if rest.is_empty() { Object::Nil } else { rest.into() });
+    }
+    ctx.track_scope(&scope)?;
+
+    Ok(scope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A module is a block, so each source starts with a value rather than a
+    // function
+    fn eval(source: &str) -> String {
+        Context::new().eval_module(format!("(let result nil) {}", source)).to_string()
+    }
+
+    #[test]
+    fn returns_hand_results_to_the_caller() {
+        assert_eq!(eval("(func add3 (x) (+ x 3)) (+ (add3 1) (add3 2))"), "9");
+        assert_eq!(eval("(func depth (n) (if (= n 0) 0 (+ 1 (depth (- n 1))))) (depth 10000)"), "10000");
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        assert_eq!(eval("(func count (n) (if (> n 0) (count (- n 1)) \"done\")) (count 200000)"), "done");
+        assert_eq!(
+            eval("(func even (n) (if (= n 0) true (odd (- n 1)))) (func odd (n) (if (= n 0) false (even (- n 1)))) (even 100001)"),
+            "false"
+        );
+    }
+
+    #[test]
+    fn tail_call_to_native_returns_from_the_frame() {
+        assert_eq!(eval("(func total (l) (len l)) (+ 1 (total (list 1 2 3)))"), "4");
+    }
+
+    #[test]
+    fn handlers_unwind_nested_frames() {
+        let source = "
+            (func inner () (throw \"deep\"))
+            (func middle () ((let x 1) (inner) (x)))
+            (+ 1 (try (middle) (catch e 10)))";
+        assert_eq!(eval(source), "11");
+    }
+
+    #[test]
+    fn handlers_do_not_outlive_their_frame() {
+        let source = "
+            (func safe () (try 1 (catch e 2)))
+            (safe)
+            (try ((safe) (throw \"after\")) (catch e (exception_message e)))";
+        assert_eq!(eval(source), "after");
+    }
+
+    #[test]
+    fn typed_catches_only_handle_their_type() {
+        assert_eq!(eval("(try (throw \"io\" \"disk full\") (catch \"io\" e (exception_message e)))"), "disk full");
+        let source = "
+            (try (try (throw \"io\" \"disk full\") (catch \"parse\" e \"inner\"))
+                 (catch e (exception_type e)))";
+        assert_eq!(eval(source), "io");
+    }
+
+    #[test]
+    fn unmatched_handlers_rethrow_after_finally() {
+        let source = "
+            (let cleaned false)
+            (let caught (try (try (throw \"a\" \"first\") (catch \"b\" e 0) (finally (set cleaned true)))
+                             (catch e (exception_message e))))
+            (list caught cleaned)";
+        assert_eq!(eval(source), "List(first true)");
+    }
+
+    #[test]
+    fn finally_runs_when_nothing_is_thrown() {
+        let source = "
+            (let cleaned false)
+            (let value (try 5 (finally (set cleaned true))))
+            (list value cleaned)";
+        assert_eq!(eval(source), "List(5 true)");
+    }
+
+    #[test]
+    fn exceptions_carry_payloads() {
+        assert_eq!(eval("(try (throw \"bad\" \"message\" (list 1 2)) (catch e (exception_payload e)))"), "List(1 2)");
+        assert_eq!(eval("(try (throw 42) (catch e (list (exception_message e) (exception_payload e))))"), "List(42 42)");
+        assert_eq!(eval("(try (throw (quote (a b))) (catch e (exception_type e)))"), "error");
+    }
+
+    #[test]
+    fn traces_list_the_frames_unwound() {
+        let source = "
+            (func inner () (throw \"deep\"))
+            (func outer () ((inner) 1))
+            (try (outer) (catch e (exception_trace e)))";
+        assert_eq!(eval(source), "List(inner (<anonymous:0>:2:28) outer (<anonymous:0>:3:29))");
+
+        // Tail calls reuse their frame, so they leave no trace of their own
+        let source = "
+            (func count (n) (if (> n 0) (count (- n 1)) (throw \"bottom\")))
+            (try (count 100) (catch e (len (exception_trace e))))";
+        assert_eq!(eval(source), "1");
+    }
+
+    #[test]
+    fn arity_is_checked_on_every_call() {
+        let source = "(func g (x) (x)) (try (g 1 2) (catch e (list (exception_type e) (exception_message e))))";
+        assert_eq!(eval(source), "List(arity g expects 1 argument, got 2)");
+    }
+
+    // Blocks used to drop the extra arguments when their first expression
+    // returned a function; now the call fails and says why.
+    #[test]
+    fn blocks_starting_with_a_function_explain_arity_errors() {
+        let hint = "the first expression of this block returned a function";
+        for &(defs, call) in &[
+            ("", "((make) (list 2))"),
+            ("(func f () ((make) (list 2)))", "(f)"),
+            ("(func f () ((let x ((make) (list 2))) (x)))", "(f)"),
+        ] {
+            let source = format!("(func make () (lambda () (1))) {} (try {} (catch e (exception_message e)))", defs, call);
+            let message = eval(&source);
+            assert!(message.starts_with("<anonymous> expects 0 arguments, got 1"), "{}", message);
+            assert!(message.contains(hint), "{}", message);
+        }
+        assert_eq!(eval("(func make () (lambda (x) (x))) ((make) 2)"), "2");
+    }
+}
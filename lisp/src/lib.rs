@@ -17,4 +17,9 @@ mod modules;
 #[cfg(target_arch = "wasm32")]
 pub mod modules;
 
-pub use self::lang::Context;
+pub use self::lang::{
+    from_argument, Context, ContextBuilder, Exception, FileSystemLoader, FromObject, GcStats, InterruptHandle,
+    IntoNativeFunction, IntoObject, LispError, MemoryLoader, ModuleLoader, Native, NativeFunction, NativeModule,
+    NativeModuleDescription, NativeReturn, Number, Object, Position, Trace, Vector,
+};
+pub use self::modules::{Gfx, Math};
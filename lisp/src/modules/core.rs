@@ -1,3 +1,7 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use super::*;
 
 pub struct Core;
@@ -12,12 +16,34 @@ impl NativeModule for Core {
         desc.register_function("char", char);
         desc.register_function("string", string);
         desc.register_function("trim", trim);
+        desc.register_typed_function("split", split);
+        desc.register_function("join", join);
+        desc.register_typed_function("substring", substring);
+        desc.register_typed_function("replace", replace);
+        desc.register_function("find", find);
+        desc.register_typed_function("index_of", index_of);
+        desc.register_typed_function("starts_with", starts_with);
+        desc.register_typed_function("ends_with", ends_with);
+        desc.register_typed_function("upper", upper);
+        desc.register_typed_function("lower", lower);
+        desc.register_function("pad_left", pad_left);
+        desc.register_function("pad_right", pad_right);
+        desc.register_function("repeat", repeat);
+        desc.register_typed_function("ord", ord);
         desc.register_function("concat", concat);
         desc.register_function("print", print);
         desc.register_function("println", println);
+        desc.register_function("format", format);
+        desc.register_function("printf", printf);
+        desc.register_function("printfln", printfln);
+        desc.register_function("repr", repr);
+        desc.register_function("write", write);
+        desc.register_function("writeln", writeln);
         desc.register_function("read", read);
         desc.register_function("readln", readln);
         desc.register_function("eval", eval);
+        desc.register_function("macroexpand", macroexpand);
+        desc.register_function("gensym", gensym);
         desc.register_function("add", add);
         desc.register_function("sub", sub);
         desc.register_function("mul", mul);
@@ -29,16 +55,47 @@ impl NativeModule for Core {
         desc.register_function("eq", eq);
         desc.register_function("gt", gt);
         desc.register_function("lt", lt);
+        desc.register_function("compare", compare);
+        desc.register_function("identical", identical);
         desc.register_function("and", and);
         desc.register_function("or", or);
         desc.register_function("map", map);
+        desc.register_function("filter", filter);
+        desc.register_function("reduce", reduce);
+        desc.register_function("fold", reduce);
+        desc.register_function("for_each", for_each);
+        desc.register_function("any", any);
+        desc.register_function("all", all);
+        desc.register_function("sort_by", sort_by);
+        desc.register_function("sort", sort);
+        desc.register_function("range", range);
+        desc.register_function("reverse", reverse);
+        desc.register_function("append", append);
+        desc.register_function("take", take);
+        desc.register_function("drop", drop);
+        desc.register_function("zip", zip);
+        desc.register_function("enumerate", enumerate);
+        desc.register_function("flatten", flatten);
         desc.register_function("fill", fill);
         desc.register_function("cons", cons);
         desc.register_function("car", car);
         desc.register_function("cdr", cdr);
         desc.register_function("nth", nth);
         desc.register_function("len", len);
+        desc.register_function("get", get);
+        desc.register_function("assoc", assoc);
+        desc.register_function("dissoc", dissoc);
+        desc.register_function("keys", keys);
+        desc.register_function("values", values);
+        desc.register_function("has", has);
+        desc.register_function("throw", throw);
+        desc.register_function("exception_type", exception_type);
+        desc.register_function("exception_message", exception_message);
+        desc.register_function("exception_payload", exception_payload);
+        desc.register_function("exception_trace", exception_trace);
         desc.register_function("yield_loop", yield_loop);
+        desc.register_function("gc", gc);
+        desc.register_function("gc_stats", gc_stats);
         desc.register_function("debug_scopes", debug_scopes);
 
         desc
@@ -140,84 +197,441 @@ pub fn integer_impl(obj: &Object) -> Number {
     }
 }
 
-pub fn string(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn string(ctx: &mut Context, args: Vec<Object>) -> Object {
     if let Some(obj) = args.get(0) {
-        string_impl(obj).into()
+        string_impl(ctx, obj).into()
     } else {
         "".into()
     }
 }
 
-pub fn string_impl(obj: &Object) -> String {
-    obj.to_string()
+pub fn string_impl(ctx: &Context, obj: &Object) -> String {
+    obj.text(ctx).to_string()
 }
 
 pub fn char(_ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
-    let c = args.next().map(|v| match integer_impl(&v) {
-        Number::Integer(i) if i >= 0 && i <= 0x7f => i as u8 as char,
-        _ => '\0',
+    let c = args.next().and_then(|v| match integer_impl(&v) {
+        Number::Integer(i) if i >= 0 && i <= i64::from(u32::max_value()) => ::std::char::from_u32(i as u32),
+        _ => None,
     }).unwrap_or('\0');
 
     c.to_string().into()
 }
 
-pub fn trim(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn trim(ctx: &mut Context, args: Vec<Object>) -> Object {
     match args.into_iter().next() {
-        Some(obj) => string_impl(&obj).trim().into(),
+        Some(obj) => string_impl(ctx, &obj).trim().into(),
         None => "".into()
     }
 }
 
-pub fn concat(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    concat_impl(args).into()
+// Strings are indexed by character, as `nth` does, rather than by byte
+
+/// Splits on `separator`, on whitespace when there is none, or into
+/// characters when it is empty.
+pub fn split(string: String, separator: Option<String>) -> Vec<String> {
+    match separator {
+        None => string.split_whitespace().map(String::from).collect(),
+        Some(ref separator) if separator.is_empty() => string.chars().map(String::from).collect(),
+        Some(ref separator) => string.split(separator.as_str()).map(String::from).collect(),
+    }
+}
+
+pub fn join(ctx: &mut Context, args: Vec<Object>) -> Object {
+    join_impl(ctx, &args).unwrap_or_else(|exception| ctx.raise(exception))
+}
+
+fn join_impl(ctx: &Context, args: &[Object]) -> Result<Object, Exception> {
+    let items: Vec<Object> = from_argument("join", 1, argument(args, 0))?;
+    let separator: Option<String> = from_argument("join", 2, argument(args, 1))?;
+
+    let strings: Vec<_> = items.iter().map(|item| string_impl(ctx, item)).collect();
+    Ok(strings.join(separator.as_ref().map_or("", String::as_str)).into())
+}
+
+/// The characters from `start` up to `end`, or the end of the string, with
+/// both clamped to the string.
+pub fn substring(string: String, start: i64, end: Option<i64>) -> String {
+    let len = string.chars().count() as i64;
+    let clamp = |idx: i64| ::std::cmp::min(::std::cmp::max(idx, 0), len) as usize;
+    let (start, end) = (clamp(start), clamp(end.unwrap_or(len)));
+    string.chars().skip(start).take(end.saturating_sub(start)).collect()
+}
+
+pub fn replace(string: String, from: String, to: String) -> String {
+    if from.is_empty() {
+        return string;
+    }
+    string.replace(from.as_str(), &to)
+}
+
+pub fn index_of(string: String, needle: String) -> Option<i64> {
+    string.find(needle.as_str()).map(|byte| string[..byte].chars().count() as i64)
+}
+
+pub fn starts_with(string: String, prefix: String) -> bool {
+    string.starts_with(prefix.as_str())
+}
+
+pub fn ends_with(string: String, suffix: String) -> bool {
+    string.ends_with(suffix.as_str())
+}
+
+pub fn upper(string: String) -> String {
+    string.to_uppercase()
+}
+
+pub fn lower(string: String) -> String {
+    string.to_lowercase()
 }
 
-pub fn concat_impl(args: Vec<Object>) -> String {
+pub fn pad_left(ctx: &mut Context, args: Vec<Object>) -> Object {
+    pad_impl(ctx, &args, "pad_left").unwrap_or_else(|exception| ctx.raise(exception))
+}
+
+pub fn pad_right(ctx: &mut Context, args: Vec<Object>) -> Object {
+    pad_impl(ctx, &args, "pad_right").unwrap_or_else(|exception| ctx.raise(exception))
+}
+
+// Pads with the first character of the fill, or spaces, up to `width`
+// characters
+fn pad_impl(ctx: &mut Context, args: &[Object], name: &str) -> Result<Object, Exception> {
+    let string: String = from_argument(name, 1, argument(args, 0))?;
+    let width: i64 = from_argument(name, 2, argument(args, 1))?;
+    let fill: Option<String> = from_argument(name, 3, argument(args, 2))?;
+
+    let fill = fill.and_then(|fill| fill.chars().next()).unwrap_or(' ');
+    let missing = (::std::cmp::max(width, 0) as usize).saturating_sub(string.chars().count());
+    ctx.check_allocation(missing.saturating_mul(fill.len_utf8()).saturating_add(string.len()))?;
+
+    let padding: String = ::std::iter::repeat(fill).take(missing).collect();
+    let padded = if name == "pad_left" { padding + &string } else { string + &padding };
+    Ok(padded.into())
+}
+
+pub fn repeat(ctx: &mut Context, args: Vec<Object>) -> Object {
+    repeat_impl(ctx, &args).unwrap_or_else(|exception| ctx.raise(exception))
+}
+
+fn repeat_impl(ctx: &mut Context, args: &[Object]) -> Result<Object, Exception> {
+    let string: String = from_argument("repeat", 1, argument(args, 0))?;
+    let count: i64 = from_argument("repeat", 2, argument(args, 1))?;
+
+    let count = ::std::cmp::max(count, 0) as usize;
+    ctx.check_allocation(string.len().saturating_mul(count))?;
+    Ok(string.repeat(count).into())
+}
+
+/// The code point of the first character, or nil for an empty string.
+pub fn ord(string: String) -> Option<i64> {
+    string.chars().next().map(|c| i64::from(c as u32))
+}
+
+fn argument(args: &[Object], idx: usize) -> &Object {
+    args.get(idx).unwrap_or(&Object::Nil)
+}
+
+pub fn concat(ctx: &mut Context, args: Vec<Object>) -> Object {
+    concat_impl(ctx, args).into()
+}
+
+pub fn concat_impl(ctx: &Context, args: Vec<Object>) -> String {
     let strings: Vec<_> = args
         .into_iter()
         .map(|s| match s {
-            Object::List(list) => concat_impl(list.to_vec()),
-            _ => string_impl(&s)
+            Object::List(list) => concat_impl(ctx, list.to_vec()),
+            _ => string_impl(ctx, &s)
         })
         .collect();
 
     strings.concat()
 }
 
-pub fn print(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    platform::print(concat_impl(args));
+pub fn print(ctx: &mut Context, args: Vec<Object>) -> Object {
+    platform::print(concat_impl(ctx, args));
+
+    Object::Nil
+}
+
+pub fn println(ctx: &mut Context, args: Vec<Object>) -> Object {
+    platform::print_line(concat_impl(ctx, args));
+
+    Object::Nil
+}
+
+pub fn format(ctx: &mut Context, args: Vec<Object>) -> Object {
+    match format_args(ctx, &args, "format") {
+        Ok(string) => string.into(),
+        Err(exception) => ctx.raise(exception),
+    }
+}
+
+pub fn printf(ctx: &mut Context, args: Vec<Object>) -> Object {
+    match format_args(ctx, &args, "printf") {
+        Ok(string) => platform::print(string),
+        Err(exception) => return ctx.raise(exception),
+    }
+
+    Object::Nil
+}
+
+pub fn printfln(ctx: &mut Context, args: Vec<Object>) -> Object {
+    match format_args(ctx, &args, "printfln") {
+        Ok(string) => platform::print_line(string),
+        Err(exception) => return ctx.raise(exception),
+    }
+
+    Object::Nil
+}
+
+fn format_args(ctx: &mut Context, args: &[Object], name: &str) -> Result<String, Exception> {
+    let template: String = from_argument(name, 1, argument(args, 0))?;
+    format_impl(ctx, &template, args.get(1..).unwrap_or(&[]))
+}
+
+/// Fills the placeholders in `template` with `args`.
+///
+/// `{}` takes the next argument and `{n}` the argument at `n`, counting from
+/// 0, followed by an optional spec after a colon: `[[fill]align][0][width]
+/// [.precision][type]`. Alignment is `<`, `>` or `^`, and the type is `x`,
+/// `X`, `b` or `o` for integers in hex, binary or octal, or `?` for the
+/// value's `repr`. Negative integers keep their sign, so `{:x}` writes -255
+/// as `-ff`. Precision rounds numbers and cuts strings short. `{{` and
+/// `}}` write braces.
+pub fn format_impl(ctx: &mut Context, template: &str, args: &[Object]) -> Result<String, Exception> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    let mut next = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => { chars.next(); result.push('{'); },
+            '}' if chars.peek() == Some(&'}') => { chars.next(); result.push('}'); },
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(format_exception("unclosed placeholder in template")),
+                    }
+                }
+
+                let (position, spec) = match placeholder.find(':') {
+                    Some(split) => (&placeholder[..split], &placeholder[split + 1..]),
+                    None => (&placeholder[..], ""),
+                };
+                let idx = if position.is_empty() {
+                    next += 1;
+                    next - 1
+                } else {
+                    position.parse().map_err(|_| format_exception(format!("invalid placeholder {{{}}}", placeholder)))?
+                };
+                let spec = FormatSpec::parse(spec)
+                    .ok_or_else(|| format_exception(format!("invalid placeholder {{{}}}", placeholder)))?;
+                ctx.check_allocation(spec.width)?;
+                let arg = args
+                    .get(idx)
+                    .ok_or_else(|| format_exception(format!("no argument {} for placeholder {{{}}}", idx, placeholder)))?;
+                result.push_str(&spec.apply(ctx, arg)?);
+            },
+            '}' => return Err(format_exception("unmatched } in template")),
+            c => result.push(c),
+        }
+    }
+
+    Ok(result)
+}
+
+fn format_exception<T: Into<String>>(message: T) -> Exception {
+    Exception::new("format", message)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+struct FormatSpec {
+    fill: char,
+    align: Option<Align>,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+impl FormatSpec {
+    fn parse(spec: &str) -> Option<FormatSpec> {
+        fn align(c: char) -> Option<Align> {
+            match c {
+                '<' => Some(Align::Left),
+                '>' => Some(Align::Right),
+                '^' => Some(Align::Center),
+                _ => None,
+            }
+        }
+        fn digits(chars: &[char], idx: &mut usize) -> Option<usize> {
+            let start = *idx;
+            while chars.get(*idx).map_or(false, |c| c.is_ascii_digit()) {
+                *idx += 1;
+            }
+            chars[start..*idx].iter().collect::<String>().parse().ok()
+        }
+
+        let chars: Vec<char> = spec.chars().collect();
+        let mut result = FormatSpec { fill: ' ', align: None, zero: false, width: 0, precision: None, kind: None };
+        let mut idx = 0;
+        if let Some(aligned) = chars.get(1).and_then(|&c| align(c)) {
+            result.fill = chars[0];
+            result.align = Some(aligned);
+            idx = 2;
+        } else if let Some(aligned) = chars.get(0).and_then(|&c| align(c)) {
+            result.align = Some(aligned);
+            idx = 1;
+        }
+        if chars.get(idx) == Some(&'0') {
+            result.zero = true;
+            idx += 1;
+        }
+        result.width = digits(&chars, &mut idx).unwrap_or(0);
+        if chars.get(idx) == Some(&'.') {
+            idx += 1;
+            result.precision = Some(digits(&chars, &mut idx)?);
+        }
+        if let Some(&kind) = chars.get(idx) {
+            if !"xXbo?".contains(kind) {
+                return None;
+            }
+            result.kind = Some(kind);
+            idx += 1;
+        }
+
+        if idx == chars.len() { Some(result) } else { None }
+    }
+
+    fn apply(&self, ctx: &Context, arg: &Object) -> Result<String, Exception> {
+        let (body, numeric) = match (self.kind, arg) {
+            (Some('?'), _) => (arg.repr(ctx).to_string(), false),
+            (Some(kind), &Object::Number(Number::Integer(value))) => {
+                let sign = if value < 0 { "-" } else { "" };
+                let magnitude = value.unsigned_abs();
+                let body = match kind {
+                    'x' => format!("{}{:x}", sign, magnitude),
+                    'X' => format!("{}{:X}", sign, magnitude),
+                    'b' => format!("{}{:b}", sign, magnitude),
+                    _ => format!("{}{:o}", sign, magnitude),
+                };
+                (body, true)
+            },
+            (Some(kind), _) => {
+                return Err(Exception::new("type", format!("format expects an integer for {{:{}}}, got {}", kind, type_name(arg))));
+            },
+            (None, &Object::Number(value)) => match self.precision {
+                Some(precision) => (format!("{:.*}", precision, value.as_float()), true),
+                None => (string_impl(ctx, arg), true),
+            },
+            (None, _) => match self.precision {
+                Some(precision) => (string_impl(ctx, arg).chars().take(precision).collect(), false),
+                None => (string_impl(ctx, arg), false),
+            },
+        };
+
+        let missing = self.width.saturating_sub(body.chars().count());
+        if missing == 0 {
+            return Ok(body);
+        }
+        // Zeros go between the sign and the digits
+        if self.zero && numeric && self.align.is_none() {
+            let (sign, digits) = if body.starts_with('-') { body.split_at(1) } else { body.split_at(0) };
+            return Ok(format!("{}{}{}", sign, "0".repeat(missing), digits));
+        }
+
+        let padding = |count: usize| ::std::iter::repeat(self.fill).take(count).collect::<String>();
+        let align = self.align.unwrap_or(if numeric { Align::Right } else { Align::Left });
+        Ok(match align {
+            Align::Left => body + &padding(missing),
+            Align::Right => padding(missing) + &body,
+            Align::Center => padding(missing / 2) + &body + &padding(missing - missing / 2),
+        })
+    }
+}
+
+pub fn repr(ctx: &mut Context, args: Vec<Object>) -> Object {
+    match args.get(0) {
+        Some(obj) => obj.repr(ctx).to_string().into(),
+        None => "nil".into(),
+    }
+}
+
+// Arguments are separated so that several values written at once read back
+// as several values
+fn write_impl(ctx: &Context, args: &[Object]) -> String {
+    args.iter().map(|arg| arg.repr(ctx).to_string()).collect::<Vec<_>>().join(" ")
+}
+
+pub fn write(ctx: &mut Context, args: Vec<Object>) -> Object {
+    platform::print(write_impl(ctx, &args));
 
     Object::Nil
 }
 
-pub fn println(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    platform::print_line(concat_impl(args));
+pub fn writeln(ctx: &mut Context, args: Vec<Object>) -> Object {
+    platform::print_line(write_impl(ctx, &args));
 
     Object::Nil
 }
 
-pub fn read(_ctx: &mut Context, _args: Vec<Object>) -> Object {
+pub fn read(ctx: &mut Context, _args: Vec<Object>) -> Object {
     use std::io::Read;
+    if !ctx.allows_console_input() {
+        return ctx.raise(console_denied());
+    }
     let mut buf = [0; 1];
     let _ = ::std::io::stdin().read_exact(&mut buf);
 
     (buf[0] as i64).into()
 }
 
-pub fn readln(_ctx: &mut Context, _args: Vec<Object>) -> Object {
+pub fn readln(ctx: &mut Context, _args: Vec<Object>) -> Object {
+    if !ctx.allows_console_input() {
+        return ctx.raise(console_denied());
+    }
+
     platform::read_line().into()
 }
 
+fn console_denied() -> Exception {
+    Exception::new("permission", "console input is not allowed")
+}
+
 pub fn eval(ctx: &mut Context, args: Vec<Object>) -> Object {
     if let Some(obj) = args.get(0) {
-        let source = string_impl(obj);
+        let source = string_impl(ctx, obj);
         ctx.eval_module(source)
     } else {
         Object::Nil
     }
 }
 
+pub fn macroexpand(ctx: &mut Context, args: Vec<Object>) -> Object {
+    match args.into_iter().next() {
+        Some(Object::Quote(quote)) => match ctx.macroexpand(quote.token()) {
+            Ok(token) => Quote::new(token).into(),
+            Err(exception) => ctx.raise(exception),
+        },
+        Some(obj) => obj,
+        None => Object::Nil,
+    }
+}
+
+pub fn gensym(ctx: &mut Context, _args: Vec<Object>) -> Object {
+    let ident = ctx.gensym();
+    Quote::new(Token::Ident(ident)).into()
+}
+
 pub fn add(_ctx: &mut Context, args: Vec<Object>) -> Object {
     use std::ops::*;
     math_op_impl(args, i64::add, f64::add).into()
@@ -233,16 +647,36 @@ pub fn mul(_ctx: &mut Context, args: Vec<Object>) -> Object {
     math_op_impl(args, i64::mul, f64::mul).into()
 }
 
-pub fn div(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn div(ctx: &mut Context, args: Vec<Object>) -> Object {
     use std::ops::*;
+    if divides_by_zero(&args) {
+        return ctx.raise(Exception::new("arithmetic", "integer division by zero"));
+    }
     math_op_impl(args, i64::div, f64::div).into()
 }
 
-pub fn rem(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn rem(ctx: &mut Context, args: Vec<Object>) -> Object {
     use std::ops::*;
+    if divides_by_zero(&args) {
+        return ctx.raise(Exception::new("arithmetic", "integer division by zero"));
+    }
     math_op_impl(args, i64::rem, f64::rem).into()
 }
 
+fn divides_by_zero(args: &[Object]) -> bool {
+    let mut args = args.iter().map(number_impl);
+    let mut is_float = match args.next() {
+        Some(Number::Float(_)) => true,
+        _ => false,
+    };
+
+    args.any(|next| match next {
+        Number::Integer(0) if !is_float => true,
+        Number::Float(_) => { is_float = true; false },
+        _ => false,
+    })
+}
+
 pub fn math_op_impl(args: Vec<Object>, op_i: fn(i64, i64) -> i64, op_f: fn(f64, f64) -> f64) -> Number {
     let mut args = args.into_iter()
             .map(|n| number_impl(&n));
@@ -275,74 +709,68 @@ pub fn list(_ctx: &mut Context, args: Vec<Object>) -> Object {
     args.into()
 }
 
-fn map_impl<F: Fn(&Object) -> Object>(obj: Object, func: F) -> Object {
-    match obj {
-        Object::List(list) => list.iter().map(|o| func(o)).collect::<Vec<_>>().into(),
-        _ => func(&obj)
-    }
-}
-
-fn reduce_impl<F: Fn(Object, &Object) -> Object>(obj: Object, init: Object, func: F) -> Object {
-    match obj {
-        Object::List(list) => list.iter().fold(init, |acc, o| func(acc, o)),
-        _ => func(init, &obj)
-    }
-}
-
-//Should do this properly with types instead of string cmping everything eventually
+// Objects compare with a total order so NaN can be sorted and used as a map
+// key, but like every other numeric comparison eq follows IEEE: NaN is never
+// equal to anything, itself included.
 pub fn eq(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let args: Vec<_> = args
-        .into_iter()
-        .map(|o| string_impl(&o))
-        .collect();
-
+    let is_nan = |obj: &Object| match *obj {
+        Object::Number(Number::Float(n)) => n.is_nan(),
+        _ => false,
+    };
     let equal = args
         .windows(2)
-        .all(|pair| pair[0] == pair[1]);
+        .all(|pair| !is_nan(&pair[0]) && !is_nan(&pair[1]) && pair[0] == pair[1]);
 
     equal.into()
 }
 
-pub fn gt(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn gt(ctx: &mut Context, args: Vec<Object>) -> Object {
+    order_impl(ctx, args)
+        .map(|o| (o == Ordering::Greater).into())
+        .unwrap_or(Object::Nil)
+}
+
+pub fn lt(ctx: &mut Context, args: Vec<Object>) -> Object {
+    order_impl(ctx, args)
+        .map(|o| (o == Ordering::Less).into())
+        .unwrap_or(Object::Nil)
+}
+
+// Values of different types have an order for sorting, but asking whether one
+// is greater than the other is almost certainly a mistake.
+fn order_impl(ctx: &mut Context, args: Vec<Object>) -> Option<Ordering> {
     let mut args = args.into_iter();
     let left = args.next().unwrap_or(Object::Nil);
     let right = args.next().unwrap_or(Object::Nil);
 
-    match (left, right) {
-        (Object::Number(Number::Integer(l)),
-         Object::Number(Number::Integer(r))) => (l > r).into(),
-        (Object::Number(Number::Float(l)),
-         Object::Number(Number::Float(r))) => (l > r).into(),
-        (Object::Number(Number::Integer(l)),
-         Object::Number(Number::Float(r))) => (l as f64 > r).into(),
-        (Object::Number(Number::Float(l)),
-         Object::Number(Number::Integer(r))) => (l > r as f64).into(),
-        (Object::String(l), Object::String(r)) => (l > r).into(),
-        (Object::Boolean(l), Object::Boolean(r)) => (l > r).into(),
-        _ => Object::Nil
+    if type_name(&left) == type_name(&right) {
+        Some(left.compare(&right))
+    } else {
+        ctx.raise(Exception::new("type", format!("cannot compare {} with {}", type_name(&left), type_name(&right))));
+        None
     }
 }
 
-pub fn lt(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn compare(_ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
     let left = args.next().unwrap_or(Object::Nil);
     let right = args.next().unwrap_or(Object::Nil);
 
-    match (left, right) {
-        (Object::Number(Number::Integer(l)),
-         Object::Number(Number::Integer(r))) => (l < r).into(),
-        (Object::Number(Number::Float(l)),
-         Object::Number(Number::Float(r))) => (l < r).into(),
-        (Object::Number(Number::Integer(l)),
-         Object::Number(Number::Float(r))) => ((l as f64) < r).into(),
-        (Object::Number(Number::Float(l)),
-         Object::Number(Number::Integer(r))) => (l < r as f64).into(),
-        (Object::String(l), Object::String(r)) => (l < r).into(),
-        (Object::Boolean(l), Object::Boolean(r)) => (l < r).into(),
-        _ => Object::Nil
+    match left.compare(&right) {
+        Ordering::Less => (-1).into(),
+        Ordering::Equal => 0.into(),
+        Ordering::Greater => 1.into(),
     }
 }
 
+pub fn identical(_ctx: &mut Context, args: Vec<Object>) -> Object {
+    let same = args
+        .windows(2)
+        .all(|pair| pair[0].identical(&pair[1]));
+
+    same.into()
+}
+
 pub fn _bool(_ctx: &mut Context, args: Vec<Object>) -> Object {
     if let Some(val) = args.into_iter().next() {
         bool_impl(&val).into()
@@ -383,29 +811,352 @@ pub fn bool_impl(obj: &Object) -> bool {
 }
 
 pub fn map(ctx: &mut Context, args: Vec<Object>) -> Object {
+    map_impl(ctx, args).into()
+}
+
+/// Calls a function with each character of a string, item of a list or key
+/// and value of a map for its effects, discarding the results.
+pub fn for_each(ctx: &mut Context, args: Vec<Object>) -> Object {
+    map_impl(ctx, args);
+
+    Object::Nil
+}
+
+fn map_impl(ctx: &mut Context, args: Vec<Object>) -> Vec<Object> {
     let mut args = args.into_iter();
     let target = args.next();
     let callee = args.next().unwrap_or(Object::Nil);
 
-    let result = match target {
+    match target {
         Some(Object::String(s)) => s.chars().map(|c| {
             let s = c.to_string();
             callee.clone().call(ctx, vec![s.into()])
         }).collect(),
-        Some(Object::List(l)) => l.iter().filter_map(|i| {
-            match callee.clone().call(ctx, vec![i.clone()]) {
-                Object::Nil => None,
-                item @ _ => Some(item),
-            }
-        }).collect(),
+        Some(Object::List(l)) => l.iter().map(|i| callee.clone().call(ctx, vec![i.clone()])).collect(),
+        Some(Object::Map(m)) => sorted_entries(&m)
+            .into_iter()
+            .map(|(k, v)| callee.clone().call(ctx, vec![k.to_object(), v.clone()]))
+            .collect(),
         None | Some(Object::Nil) => vec![Object::Nil],
         Some(a) => { vec![callee.call(ctx, vec![a])] }
+    }
+}
+
+// The sequence builtins below take a list, nil as the empty list, or a string
+// as its characters, and call back into lisp functions or native ones alike.
+// They stop at the first exception a callback raises.
+
+fn sequence_arg(name: &str, position: usize, arg: &Object) -> Result<Vec<Object>, Exception> {
+    match *arg {
+        Object::String(ref s) => Ok(s.chars().map(|c| c.to_string().into()).collect()),
+        _ => from_argument(name, position, arg),
+    }
+}
+
+// Calls `callee`, handing back None once it has raised
+fn callback(ctx: &mut Context, callee: &Object, args: Vec<Object>) -> Option<Object> {
+    let result = callee.clone().call(ctx, args);
+    if ctx.has_exception() { None } else { Some(result) }
+}
+
+fn raise_or_nil(ctx: &mut Context, result: Result<Object, Exception>) -> Object {
+    match result {
+        Ok(val) => val,
+        Err(exception) => ctx.raise(exception),
+    }
+}
+
+/// The items a predicate holds for, or the truthy items when there is none.
+pub fn filter(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("filter", 1, argument(&args, 0)).map(|items| {
+        let predicate = argument(&args, 1);
+        let mut kept = Vec::new();
+        for item in items {
+            let keep = match *predicate {
+                Object::Nil => bool_impl(&item),
+                _ => match callback(ctx, predicate, vec![item.clone()]) {
+                    Some(keep) => bool_impl(&keep),
+                    None => return Object::Nil,
+                },
+            };
+            if keep {
+                kept.push(item);
+            }
+        }
+
+        kept.into()
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+/// Folds the items into one value by calling `(func acc item)` for each,
+/// starting from `init` or, without one, from the first item.
+pub fn reduce(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("reduce", 1, argument(&args, 0)).map(|items| {
+        let func = argument(&args, 1);
+        let mut items = items.into_iter();
+        let mut acc = match args.get(2) {
+            Some(init) => init.clone(),
+            None => items.next().unwrap_or(Object::Nil),
+        };
+        for item in items {
+            acc = match callback(ctx, func, vec![acc, item]) {
+                Some(acc) => acc,
+                None => return Object::Nil,
+            };
+        }
+
+        acc
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+/// Whether the predicate, or the item itself without one, holds for any item.
+pub fn any(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("any", 1, argument(&args, 0))
+        .map(|items| test_items(ctx, items, argument(&args, 1), true).unwrap_or(Object::Nil));
+    raise_or_nil(ctx, result)
+}
+
+/// Whether the predicate, or the item itself without one, holds for every
+/// item.
+pub fn all(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("all", 1, argument(&args, 0))
+        .map(|items| test_items(ctx, items, argument(&args, 1), false).unwrap_or(Object::Nil));
+    raise_or_nil(ctx, result)
+}
+
+// Stops at the first item the predicate gives `stop_on` for
+fn test_items(ctx: &mut Context, items: Vec<Object>, predicate: &Object, stop_on: bool) -> Option<Object> {
+    for item in items {
+        let held = match *predicate {
+            Object::Nil => bool_impl(&item),
+            _ => bool_impl(&callback(ctx, predicate, vec![item])?),
+        };
+        if held == stop_on {
+            return Some(stop_on.into());
+        }
+    }
+
+    Some((!stop_on).into())
+}
+
+/// The first item a predicate holds for. Substrings are looked up with
+/// `index_of`.
+pub fn find(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("find", 1, argument(&args, 0)).map(|items| {
+        let predicate = argument(&args, 1);
+        for item in items {
+            match callback(ctx, predicate, vec![item.clone()]) {
+                Some(found) if bool_impl(&found) => return item,
+                Some(_) => (),
+                None => return Object::Nil,
+            }
+        }
+
+        Object::Nil
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+pub fn sort(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("sort", 1, argument(&args, 0)).map(|mut items| {
+        items.sort_by(Object::compare);
+        items.into()
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+/// Sorts with a comparator returning a negative number, zero or a positive
+/// number, as `compare` does. The sort is stable.
+pub fn sort_by(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("sort_by", 1, argument(&args, 0)).and_then(|items| {
+        let comparator = argument(&args, 1);
+        let mut failed = None;
+        let sorted = merge_sort(items, &mut |a, b| match callback(ctx, comparator, vec![a.clone(), b.clone()])? {
+            Object::Number(order) => Some(order.as_float().partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+            other => {
+                failed = Some(Exception::new("type", format!("sort_by expects the comparator to return a number, got {}", type_name(&other))));
+                None
+            },
+        });
+
+        match (sorted, failed) {
+            (_, Some(exception)) => Err(exception),
+            (Some(sorted), None) => Ok(sorted.into()),
+            (None, None) => Ok(Object::Nil),
+        }
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+// A stable merge sort that asks `compare` about each pair at most once, so a
+// comparator that contradicts itself can only give an odd order, and that
+// gives up as soon as `compare` does.
+fn merge_sort<F>(mut items: Vec<Object>, compare: &mut F) -> Option<Vec<Object>>
+where
+    F: FnMut(&Object, &Object) -> Option<Ordering>,
+{
+    if items.len() < 2 {
+        return Some(items);
+    }
+
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, compare)?;
+    let right = merge_sort(right, compare)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        if compare(a, b)? == Ordering::Greater {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+
+    Some(merged)
+}
+
+/// Integers from `start`, or 0, up to but not including `end`, counting by
+/// `step`, or 1.
+pub fn range(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = range_impl(ctx, &args);
+    raise_or_nil(ctx, result)
+}
+
+fn range_impl(ctx: &mut Context, args: &[Object]) -> Result<Object, Exception> {
+    let first: i64 = from_argument("range", 1, argument(args, 0))?;
+    let second: Option<i64> = from_argument("range", 2, argument(args, 1))?;
+    let step: Option<i64> = from_argument("range", 3, argument(args, 2))?;
+
+    let (start, end) = match second {
+        Some(end) => (first, end),
+        None => (0, first),
     };
+    let step = step.unwrap_or(1);
+    if step == 0 {
+        return Err(Exception::new("arithmetic", "range step cannot be zero"));
+    }
 
-    result.into()
+    let span = if step > 0 { i128::from(end) - i128::from(start) } else { i128::from(start) - i128::from(end) };
+    let count = if span <= 0 { 0 } else { ((span - 1) / i128::from(step).abs() + 1) as usize };
+    ctx.check_allocation(count.saturating_mul(::std::mem::size_of::<Object>()))?;
+
+    Ok((0..count).map(|idx| Object::from(start + idx as i64 * step)).collect::<Vec<_>>().into())
 }
 
-pub fn fill(_ctx: &mut Context, args: Vec<Object>) -> Object {
+/// A list's items or a string's characters in reverse order.
+pub fn reverse(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = match *argument(&args, 0) {
+        Object::String(ref s) => Ok(s.chars().rev().collect::<String>().into()),
+        ref arg => from_argument::<Vec<Object>>("reverse", 1, arg).map(|mut items| {
+            items.reverse();
+            items.into()
+        }),
+    };
+
+    raise_or_nil(ctx, result)
+}
+
+/// Joins lists end to end. Arguments that are not lists are added as items.
+pub fn append(_ctx: &mut Context, args: Vec<Object>) -> Object {
+    let mut items = Vec::new();
+    for arg in args {
+        match arg {
+            Object::List(list) => items.extend(list.iter().cloned()),
+            Object::Nil => (),
+            other => items.push(other),
+        }
+    }
+
+    items.into()
+}
+
+pub fn take(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = take_impl(&args, "take");
+    raise_or_nil(ctx, result)
+}
+
+pub fn drop(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = take_impl(&args, "drop");
+    raise_or_nil(ctx, result)
+}
+
+fn take_impl(args: &[Object], name: &str) -> Result<Object, Exception> {
+    let items = sequence_arg(name, 1, argument(args, 0))?;
+    let count: i64 = from_argument(name, 2, argument(args, 1))?;
+
+    let count = ::std::cmp::max(count, 0) as usize;
+    let items: Vec<Object> = if name == "take" {
+        items.into_iter().take(count).collect()
+    } else {
+        items.into_iter().skip(count).collect()
+    };
+    Ok(items.into())
+}
+
+/// Lists of the items at each index of every list, as long as the shortest.
+pub fn zip(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let lists: Result<Vec<Vec<Object>>, Exception> = args
+        .iter()
+        .enumerate()
+        .map(|(idx, arg)| sequence_arg("zip", idx + 1, arg))
+        .collect();
+    let result = lists.map(|lists| {
+        let len = lists.iter().map(Vec::len).min().unwrap_or(0);
+        (0..len)
+            .map(|idx| lists.iter().map(|list| list[idx].clone()).collect::<Vec<_>>().into())
+            .collect::<Vec<Object>>()
+            .into()
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+/// Pairs each item with its index, as `(index item)` lists.
+pub fn enumerate(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let result = sequence_arg("enumerate", 1, argument(&args, 0)).map(|items| {
+        items
+            .into_iter()
+            .enumerate()
+            .map(|(idx, item)| vec![Object::from(idx as i64), item].into())
+            .collect::<Vec<Object>>()
+            .into()
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+/// The items of nested lists, however deep, in one list.
+pub fn flatten(ctx: &mut Context, args: Vec<Object>) -> Object {
+    fn flatten_into<'a, I: IntoIterator<Item = &'a Object>>(items: I, flat: &mut Vec<Object>) {
+        for item in items {
+            match *item {
+                Object::List(ref list) => flatten_into(list.iter(), flat),
+                ref item => flat.push(item.clone()),
+            }
+        }
+    }
+
+    let result = sequence_arg("flatten", 1, argument(&args, 0)).map(|items| {
+        let mut flat = Vec::new();
+        flatten_into(&items, &mut flat);
+        flat.into()
+    });
+
+    raise_or_nil(ctx, result)
+}
+
+pub fn fill(ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
     let count = integer_impl(&args.next().unwrap_or(Object::Nil));
 
@@ -420,6 +1171,9 @@ pub fn fill(_ctx: &mut Context, args: Vec<Object>) -> Object {
     };
 
     let value = args.next().unwrap_or(Object::Nil);
+    if let Err(exception) = ctx.check_allocation(count.saturating_mul(::std::mem::size_of::<Object>())) {
+        return ctx.raise(exception);
+    }
 
     vec![value; count].into()
 }
@@ -428,7 +1182,7 @@ pub fn cons(_ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
     match (args.next(), args.next()) {
         (Some(o), Some(Object::List(l))) => {
-            let mut new_list = l.to_vec();
+            let mut new_list = (*l).clone();
             new_list.push(o);
             new_list.into()
         },
@@ -455,8 +1209,8 @@ pub fn cdr(_ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
     match args.next() {
         Some(Object::List(l)) => {
-            if l[1..].len() > 0 {
-                l[1..].to_vec().into()
+            if l.len() > 1 {
+                l.iter().skip(1).cloned().collect::<Vec<_>>().into()
             } else {
                 Object::Nil
             }
@@ -466,16 +1220,18 @@ pub fn cdr(_ctx: &mut Context, args: Vec<Object>) -> Object {
     }
 }
 
-pub fn len(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn len(ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
     match args.next() {
         Some(Object::List(l)) => (l.len() as i64).into(),
-        Some(Object::String(s)) => (s.len() as i64).into(),
-        _ => Object::Nil
+        Some(Object::String(s)) => (s.chars().count() as i64).into(),
+        Some(Object::Map(m)) => (m.len() as i64).into(),
+        Some(o) => ctx.raise(Exception::new("type", format!("len expects a list, string or map, got {}", type_name(&o)))),
+        None => ctx.raise(Exception::new("type", "len expects a list, string or map")),
     }
 }
 
-pub fn nth(_ctx: &mut Context, args: Vec<Object>) -> Object {
+pub fn nth(ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
     let collection = args.next().unwrap_or(Object::Nil);
     let index = integer_impl(&args.next().unwrap_or(Object::Nil));
@@ -507,8 +1263,8 @@ pub fn nth(_ctx: &mut Context, args: Vec<Object>) -> Object {
         },
         Object::List(l) => {
             if let Some(val) = value {
-                let mut l = l.to_vec();
-                l.get_mut(index).map(|v| *v = val);
+                let mut l = (*l).clone();
+                l.set(index, val);
                 l.into()
             } else {
                 l
@@ -517,7 +1273,7 @@ pub fn nth(_ctx: &mut Context, args: Vec<Object>) -> Object {
                     .unwrap_or(Object::Nil)
             }
         },
-        _ => Object::Nil
+        o => ctx.raise(Exception::new("type", format!("nth expects a list or string, got {}", type_name(&o)))),
     }
 }
 
@@ -543,9 +1299,7 @@ pub fn yield_loop(ctx: &mut Context, args: Vec<Object>) -> Object {
         }).collect();
 
 
-        let callback = NativeFunction {
-            ptr: yield_loop,
-        };
+        let callback = NativeFunction::new(yield_loop);
 
         ctx.do_yield(callback.into(), new_args);
     }
@@ -554,31 +1308,350 @@ pub fn yield_loop(ctx: &mut Context, args: Vec<Object>) -> Object {
 }
 
 pub fn type_of(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let _type = args.get(0).map(|obj| {
-        match *obj {
-            Object::Nil => "nil",
-            Object::Boolean(_) => "boolean",
-            Object::Number(_) => "number",
-            Object::String(_) => "string",
-            Object::List(_) => "list",
-            Object::Function(_) => "function",
-            Object::NativeFunction(_) => "nativefunction",
-            Object::NativeObject(_) => "nativeobject",
-            Object::Quote(_) => "quote",
-            Object::Exception(_) => "exception",
+    args.get(0).map(type_name).unwrap_or("nil").into()
+}
+
+pub fn type_name(obj: &Object) -> &'static str {
+    obj.type_name()
+}
+
+pub fn throw(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let mut args = args.into_iter();
+    let exception = match (args.next(), args.next()) {
+        (Some(Object::Exception(excep)), None) => (*excep).clone(),
+        (Some(message), None) => Exception::message(string_impl(ctx, &message)).with_payload(message),
+        (Some(kind), Some(message)) => {
+            let payload = args.next().unwrap_or(Object::Nil);
+            Exception::new(string_impl(ctx, &kind), string_impl(ctx, &message)).with_payload(payload)
+        },
+        (None, _) => Exception::message("nil"),
+    };
+
+    ctx.raise(exception)
+}
+
+// Nil stands in for an empty map so maps can be built up from nothing.
+fn map_arg(ctx: &mut Context, obj: Option<Object>, name: &str) -> Option<Rc<HashMap<MapKey, Object>>> {
+    match obj {
+        Some(Object::Map(m)) => Some(m),
+        None | Some(Object::Nil) => Some(Rc::new(HashMap::new())),
+        Some(o) => {
+            ctx.raise(Exception::new("type", format!("{} expects a map, got {}", name, type_name(&o))));
+            None
+        },
+    }
+}
+
+fn map_key(ctx: &mut Context, obj: &Object) -> Option<MapKey> {
+    let key = MapKey::from_object(obj);
+    if key.is_none() {
+        ctx.raise(Exception::new("type", format!("{} cannot be used as a map key", type_name(obj))));
+    }
+
+    key
+}
+
+pub fn get(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let mut args = args.into_iter();
+    let map = map_arg(ctx, args.next(), "get");
+    let key = map_key(ctx, &args.next().unwrap_or(Object::Nil));
+    let default = args.next().unwrap_or(Object::Nil);
+
+    match (map, key) {
+        (Some(map), Some(key)) => map.get(&key).cloned().unwrap_or(default),
+        _ => Object::Nil,
+    }
+}
+
+pub fn assoc(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let mut args = args.into_iter();
+    let mut map = match map_arg(ctx, args.next(), "assoc") {
+        Some(map) => map,
+        None => return Object::Nil,
+    };
+
+    while let Some(key) = args.next() {
+        let key = match map_key(ctx, &key) {
+            Some(key) => key,
+            None => return Object::Nil,
+        };
+        Rc::make_mut(&mut map).insert(key, args.next().unwrap_or(Object::Nil));
+    }
+
+    Object::Map(map)
+}
+
+pub fn dissoc(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let mut args = args.into_iter();
+    let mut map = match map_arg(ctx, args.next(), "dissoc") {
+        Some(map) => map,
+        None => return Object::Nil,
+    };
+
+    for key in args {
+        let key = match map_key(ctx, &key) {
+            Some(key) => key,
+            None => return Object::Nil,
+        };
+        if map.contains_key(&key) {
+            Rc::make_mut(&mut map).remove(&key);
+        }
+    }
+
+    Object::Map(map)
+}
+
+pub fn keys(ctx: &mut Context, args: Vec<Object>) -> Object {
+    match map_arg(ctx, args.into_iter().next(), "keys") {
+        Some(map) => sorted_entries(&map).into_iter().map(|(k, _)| k.to_object()).collect::<Vec<_>>().into(),
+        None => Object::Nil,
+    }
+}
+
+pub fn values(ctx: &mut Context, args: Vec<Object>) -> Object {
+    match map_arg(ctx, args.into_iter().next(), "values") {
+        Some(map) => sorted_entries(&map).into_iter().map(|(_, v)| v.clone()).collect::<Vec<_>>().into(),
+        None => Object::Nil,
+    }
+}
+
+pub fn has(ctx: &mut Context, args: Vec<Object>) -> Object {
+    let mut args = args.into_iter();
+    let map = map_arg(ctx, args.next(), "has");
+    let key = map_key(ctx, &args.next().unwrap_or(Object::Nil));
+
+    match (map, key) {
+        (Some(map), Some(key)) => map.contains_key(&key).into(),
+        _ => Object::Nil,
+    }
+}
+
+fn exception_arg<'a>(ctx: &mut Context, args: &'a [Object], name: &str) -> Option<&'a Exception> {
+    match args.get(0) {
+        Some(&Object::Exception(ref excep)) => Some(excep),
+        _ => {
+            ctx.raise(Exception::new("type", format!("{} expects an exception", name)));
+            None
         }
-    }).unwrap_or("nil");
+    }
+}
+
+pub fn exception_type(ctx: &mut Context, args: Vec<Object>) -> Object {
+    exception_arg(ctx, &args, "exception_type")
+        .map(|e| e.get_kind().into())
+        .unwrap_or(Object::Nil)
+}
+
+pub fn exception_message(ctx: &mut Context, args: Vec<Object>) -> Object {
+    exception_arg(ctx, &args, "exception_message")
+        .map(|e| e.get_message().into())
+        .unwrap_or(Object::Nil)
+}
+
+pub fn exception_payload(ctx: &mut Context, args: Vec<Object>) -> Object {
+    exception_arg(ctx, &args, "exception_payload")
+        .map(|e| e.get_payload().clone())
+        .unwrap_or(Object::Nil)
+}
+
+pub fn exception_trace(ctx: &mut Context, args: Vec<Object>) -> Object {
+    exception_arg(ctx, &args, "exception_trace")
+        .map(|e| e.get_trace().iter().map(|t| t.as_str()).collect::<Vec<_>>().into())
+        .unwrap_or(Object::Nil)
+}
+
+pub fn gc(ctx: &mut Context, _args: Vec<Object>) -> Object {
+    (ctx.collect_garbage() as i64).into()
+}
 
-    _type.into()
+pub fn gc_stats(ctx: &mut Context, _args: Vec<Object>) -> Object {
+    let stats = ctx.gc_stats();
+    let mut map = HashMap::new();
+    for &(key, value) in &[("collections", stats.collections), ("freed", stats.freed), ("tracked", stats.tracked)] {
+        map.insert(MapKey::String(Rc::new(key.to_string())), (value as i64).into());
+    }
+
+    map.into()
 }
 
 pub fn debug_scopes(ctx: &mut Context, _args: Vec<Object>) -> Object {
     for (idx, scope) in ctx.get_scopes().iter().enumerate() {
         platform::print_line(format!("{}:", idx));
         for (k, v) in scope {
-            platform::print_line(format!("\t{}: {}", ctx.get_ident_name(*k).unwrap_or("<unknown>"), v));
+            let name = ctx.get_ident_name(*k).unwrap_or("<unknown>");
+            let location = match *v {
+                Object::Function(ref func) => func.proto.location.as_ref().map(|l| format!(" ({})", l)),
+                _ => None,
+            };
+            platform::print_line(format!("\t{}: {}{}", name, v, location.unwrap_or_default()));
         }
     }
 
     Object::Nil
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::Context;
+    use super::*;
+
+    fn eval(source: &str) -> Object {
+        Context::new().try_eval_module(source).expect("evaluates")
+    }
+
+    #[test]
+    fn strings_are_indexed_by_character() {
+        assert_eq!(eval("(len \"héllo\")"), Object::from(5));
+        assert_eq!(eval("(nth \"héllo\" 1)"), Object::from("é"));
+        assert_eq!(eval("(nth \"héllo\" 9)"), Object::Nil);
+        assert_eq!(eval("(substring \"héllo\" 1 3)"), Object::from("él"));
+        assert_eq!(eval("(substring \"héllo\" -2 99)"), Object::from("héllo"));
+        assert_eq!(eval("(substring \"héllo\" 3)"), Object::from("lo"));
+    }
+
+    #[test]
+    fn char_and_ord_round_trip() {
+        assert_eq!(eval("(ord \"é\")"), Object::from(233));
+        assert_eq!(eval("(char 233)"), Object::from("é"));
+        assert_eq!(eval("(char (ord \"z\"))"), Object::from("z"));
+        assert_eq!(eval("(ord \"\")"), Object::Nil);
+    }
+
+    #[test]
+    fn split_and_join_round_trip() {
+        assert_eq!(eval("(split \"a,b,,c\" \",\")"), eval("(list \"a\" \"b\" \"\" \"c\")"));
+        assert_eq!(eval("(split \"  a \\t b \")"), eval("(list \"a\" \"b\")"));
+        assert_eq!(eval("(split \"hé\" \"\")"), eval("(list \"h\" \"é\")"));
+        assert_eq!(eval("(join (split \"a,b,,c\" \",\") \",\")"), Object::from("a,b,,c"));
+        assert_eq!(eval("(join (list 1 \"b\" nil))"), Object::from("1bnil"));
+    }
+
+    #[test]
+    fn format_fills_placeholders_in_order_and_by_position() {
+        assert_eq!(eval("(format \"{} and {}\" 1 \"b\")"), Object::from("1 and b"));
+        assert_eq!(eval("(format \"{1}{0}{1}\" \"a\" \"b\")"), Object::from("bab"));
+        assert_eq!(eval("(format \"{{{}}}\" 7)"), Object::from("{7}"));
+        assert_eq!(eval("(format \"{:?}\" \"a\")"), Object::from("\"a\""));
+    }
+
+    #[test]
+    fn format_applies_width_and_precision() {
+        assert_eq!(eval("(format \"[{:5}]\" 42)"), Object::from("[   42]"));
+        assert_eq!(eval("(format \"[{:5}]\" \"ab\")"), Object::from("[ab   ]"));
+        assert_eq!(eval("(format \"[{:*^6}]\" \"ab\")"), Object::from("[**ab**]"));
+        assert_eq!(eval("(format \"[{:05}]\" -42)"), Object::from("[-0042]"));
+        assert_eq!(eval("(format \"{:.2}\" 3.14159)"), Object::from("3.14"));
+        assert_eq!(eval("(format \"{:.3}\" \"abcdef\")"), Object::from("abc"));
+        assert_eq!(eval("(format \"[{:>8.1}]\" 2.25)"), Object::from("[     2.2]"));
+    }
+
+    #[test]
+    fn format_writes_integers_in_other_bases() {
+        assert_eq!(eval("(format \"{:x} {:X} {:b} {:o}\" 255 255 5 8)"), Object::from("ff FF 101 10"));
+        assert_eq!(eval("(format \"{:x}\" -255)"), Object::from("-ff"));
+        assert_eq!(eval("(format \"{:04x}\" -255)"), Object::from("-0ff"));
+    }
+
+    #[test]
+    fn format_rejects_bad_templates() {
+        let error = |source: &str| eval(&format!("(try {} (catch e (exception_type e)))", source));
+        assert_eq!(error("(format \"{\" 1)"), Object::from("format"));
+        assert_eq!(error("(format \"}\")"), Object::from("format"));
+        assert_eq!(error("(format \"{} {}\" 1)"), Object::from("format"));
+        assert_eq!(error("(format \"{:q}\" 1)"), Object::from("format"));
+        assert_eq!(error("(format \"{:x}\" 1.5)"), Object::from("type"));
+    }
+
+    #[test]
+    fn map_keeps_nil_results() {
+        assert_eq!(eval("(map (list 1 2 3) (lambda (x) (if (= x 2) nil x)))"), eval("(list 1 nil 3)"));
+    }
+
+    #[test]
+    fn sort_orders_lists_strings_and_nil() {
+        assert_eq!(eval("(sort (list 3 1 2))"), eval("(list 1 2 3)"));
+        assert_eq!(eval("(sort \"cab\")"), eval("(list \"a\" \"b\" \"c\")"));
+        assert_eq!(eval("(sort nil)"), eval("(list)"));
+        assert_eq!(eval("(try (sort 1) (catch e (exception_type e)))"), Object::from("type"));
+    }
+
+    #[test]
+    fn sort_by_is_stable_and_takes_native_comparators() {
+        let source = "(sort_by (list (list 2 \"a\") (list 1 \"b\") (list 2 \"c\") (list 1 \"d\")) \
+                      (lambda (a b) (- (car a) (car b))))";
+        assert_eq!(eval(source), eval("(list (list 1 \"b\") (list 1 \"d\") (list 2 \"a\") (list 2 \"c\"))"));
+        assert_eq!(eval("(sort_by (list 3 1 2) compare)"), eval("(list 1 2 3)"));
+        assert_eq!(eval("(sort_by \"ba\" compare)"), eval("(list \"a\" \"b\")"));
+    }
+
+    #[test]
+    fn sort_by_survives_inconsistent_comparators() {
+        let sorted = eval("(sort_by (range 50) (lambda (a b) (- (% (+ a b) 3) 1)))");
+        match sorted {
+            Object::List(list) => assert_eq!(list.len(), 50),
+            other => panic!("expected a list, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sort_by_stops_at_the_first_comparator_error() {
+        let source = "(let calls 0) \
+                      (let result (try (sort_by (list 4 3 2 1) (lambda (a b) ((set calls (+ calls 1)) (throw \"boom\" \"no\")))) \
+                                       (catch e (exception_type e)))) \
+                      (list result calls)";
+        assert_eq!(eval(source), eval("(list \"boom\" 1)"));
+        let source = "(try (sort_by (list 2 1) (lambda (a b) (concat a b))) (catch e (exception_type e)))";
+        assert_eq!(eval(source), Object::from("type"));
+    }
+
+    #[test]
+    fn take_drop_enumerate_and_flatten_accept_sequences() {
+        assert_eq!(eval("(take (list 1 2 3) 2)"), eval("(list 1 2)"));
+        assert_eq!(eval("(take \"abc\" 5)"), eval("(list \"a\" \"b\" \"c\")"));
+        assert_eq!(eval("(drop (list 1 2 3) 2)"), eval("(list 3)"));
+        assert_eq!(eval("(drop \"abc\" -1)"), eval("(list \"a\" \"b\" \"c\")"));
+        assert_eq!(eval("(drop nil 1)"), eval("(list)"));
+        assert_eq!(eval("(enumerate \"ab\")"), eval("(list (list 0 \"a\") (list 1 \"b\"))"));
+        assert_eq!(eval("(flatten (list 1 (list 2 (list 3)) nil))"), eval("(list 1 2 3 nil)"));
+        assert_eq!(eval("(flatten \"ab\")"), eval("(list \"a\" \"b\")"));
+        assert_eq!(eval("(try (take 1 2) (catch e (exception_type e)))"), Object::from("type"));
+    }
+
+    #[test]
+    fn find_takes_a_predicate() {
+        assert_eq!(eval("(find (list 1 2 3 4) (lambda (x) (> x 2)))"), Object::from(3));
+        assert_eq!(eval("(find (list 1 2) (lambda (x) (> x 2)))"), Object::Nil);
+        assert_eq!(eval("(find \"abc\" (lambda (c) (= c \"b\")))"), Object::from("b"));
+        assert_eq!(eval("(index_of \"abc\" \"c\")"), Object::from(2));
+    }
+
+    #[test]
+    fn any_and_all_stop_early() {
+        assert_eq!(eval("(any (list 1 2 3) (lambda (x) (> x 2)))"), Object::from(true));
+        assert_eq!(eval("(all (list 1 2 3) (lambda (x) (> x 2)))"), Object::from(false));
+        assert_eq!(eval("(all nil)"), Object::from(true));
+        assert_eq!(eval("(any (list nil false))"), Object::from(false));
+        let source = "(let calls 0) \
+                      (all (list 1 2 3) (lambda (x) ((set calls (+ calls 1)) (< x 2)))) \
+                      calls";
+        assert_eq!(eval(source), Object::from(2));
+    }
+
+    #[test]
+    fn range_counts_up_and_down() {
+        assert_eq!(eval("(range 3)"), eval("(list 0 1 2)"));
+        assert_eq!(eval("(range 2 5)"), eval("(list 2 3 4)"));
+        assert_eq!(eval("(range 5 0 -2)"), eval("(list 5 3 1)"));
+        assert_eq!(eval("(range 3 1)"), eval("(list)"));
+        assert_eq!(eval("(try (range 0 3 0) (catch e (exception_type e)))"), Object::from("arithmetic"));
+    }
+
+    #[test]
+    fn callback_errors_propagate() {
+        let error = |source: &str| eval(&format!("(try {} (catch e (exception_message e)))", source));
+        assert_eq!(error("(filter (list 1) (lambda (x) (throw \"boom\" \"filter\")))"), Object::from("filter"));
+        assert_eq!(error("(find (list 1) (lambda (x) (throw \"boom\" \"find\")))"), Object::from("find"));
+        assert_eq!(error("(any (list 1) (lambda (x) (throw \"boom\" \"any\")))"), Object::from("any"));
+        assert_eq!(error("(reduce (list 1 2) (lambda (a b) (throw \"boom\" \"reduce\")))"), Object::from("reduce"));
+    }
+}
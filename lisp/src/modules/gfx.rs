@@ -9,19 +9,21 @@ impl NativeModule for Gfx {
     fn register(&mut self) -> NativeModuleDescription {
         let mut desc = NativeModuleDescription::new("gfx");
 
+        // Drawing needs the context for the window, everything else is typed
+        // and raises a type exception for anything but numbers and vectors.
         desc.register_function("create_window", create_window);
-        desc.register_function("set_clear_color", set_clear_color);
-        desc.register_function("set_line_width", set_line_width);
-        desc.register_function("draw_line", draw_line);
-        desc.register_function("draw_circle", draw_circle);
-        desc.register_function("draw_line_list", draw_line_list);
-
-        desc.register_function("vec2", vec2);
-        desc.register_function("vec3", vec3);
-
-        desc.register_function("vec_x", vec_x);
-        desc.register_function("vec_y", vec_y);
-        desc.register_function("vec_z", vec_z);
+        desc.register_typed_function("set_clear_color", set_clear_color);
+        desc.register_typed_function("set_line_width", set_line_width);
+        desc.register_typed_function("draw_line", draw_line);
+        desc.register_typed_function("draw_circle", draw_circle);
+        desc.register_typed_function("draw_line_list", draw_line_list);
+
+        desc.register_typed_function("vec2", vec2);
+        desc.register_typed_function("vec3", vec3);
+
+        desc.register_typed_function("vec_x", vec_x);
+        desc.register_typed_function("vec_y", vec_y);
+        desc.register_typed_function("vec_z", vec_z);
         desc
     }
 }
@@ -72,135 +74,84 @@ pub fn create_window(ctx: &mut Context, args: Vec<Object>) -> Object {
     super::gfx_platform::create_window(ctx, args)
 }
 
-pub fn set_clear_color(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let draw_commands: Option<DrawPackage> = to_native(args.get(0));
-    let color: Option<Vector3<f32>> = to_native(args.get(1));
-    if let (Some(draw_commands), Some(color)) = (draw_commands, color) {
-        draw_commands.push(DrawCommand::ClearColor(color));
-
-        Object::create_native_object(draw_commands)
-    } else {
-        Object::Nil
-    }
+pub fn set_clear_color(draw_commands: Native<DrawPackage>, color: Native<Vector3<f32>>) -> Native<DrawPackage> {
+    draw_commands.0.push(DrawCommand::ClearColor(color.0));
+    draw_commands
 }
 
-pub fn set_line_width(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let draw_commands: Option<DrawPackage> = to_native(args.get(0));
-    let width = args.get(1)
-        .map(|a| core::float_impl(a))
-        .and_then(|i| if let Number::Float(i) = i { Some(i as f32) } else { None } );
-
-    if let (Some(draw_commands), Some(width)) = (draw_commands, width) {
-        draw_commands.push(DrawCommand::LineWidth(width));
-
-        Object::create_native_object(draw_commands)
-    } else {
-        Object::Nil
-    }
+pub fn set_line_width(draw_commands: Native<DrawPackage>, width: f64) -> Native<DrawPackage> {
+    draw_commands.0.push(DrawCommand::LineWidth(width as f32));
+    draw_commands
 }
 
-pub fn draw_line(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let draw_commands: Option<DrawPackage> = to_native(args.get(0));
-    let start: Option<Vector2<f32>> = to_native(args.get(1));
-    let end: Option<Vector2<f32>> = to_native(args.get(2));
-    let color: Option<Vector3<f32>> = to_native(args.get(3));
-    if let (Some(draw_commands), Some(start), Some(end), Some(color)) = (draw_commands, start, end, color) {
-        draw_commands.push(DrawCommand::Line(start, end, color));
-
-        Object::create_native_object(draw_commands)
-    } else {
-        Object::Nil
-    }
-}
-pub fn draw_circle(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let draw_commands: Option<DrawPackage> = to_native(args.get(0));
-    let center: Option<Vector2<f32>> = to_native(args.get(1));
-    let radius = to_float(args.get(2));
-    let color: Option<Vector3<f32>> = to_native(args.get(3));
-    if let (Some(draw_commands), Some(center), Some(color)) = (draw_commands, center, color) {
-        draw_commands.push(DrawCommand::Circle(center, radius, color));
-
-        Object::create_native_object(draw_commands)
-    } else {
-        Object::Nil
-    }
+pub fn draw_line(
+    draw_commands: Native<DrawPackage>,
+    start: Native<Vector2<f32>>,
+    end: Native<Vector2<f32>>,
+    color: Native<Vector3<f32>>,
+) -> Native<DrawPackage> {
+    draw_commands.0.push(DrawCommand::Line(start.0, end.0, color.0));
+    draw_commands
 }
 
-pub fn draw_line_list(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    match args.get(1) {
-        Some(Object::List(l)) => {
-            let points: Vec<Vector2<f32>> = l.iter().filter_map(|n| to_native(Some(n))).collect();
-            let draw_commands: Option<DrawPackage> = to_native(args.get(0));
-            let color: Option<Vector3<f32>> = to_native(args.get(2));
-
-            if points.len() < 2 { Object::Nil } else {
-                if let (Some(draw_commands), Some(color)) = (draw_commands, color) {
-                    draw_commands.push(DrawCommand::Lines(points, color));
-                    Object::create_native_object(draw_commands)
-                } else {
-                    Object::Nil
-                }
-            }
-        },
-        _ => Object::Nil
-    }
+pub fn draw_circle(
+    draw_commands: Native<DrawPackage>,
+    center: Native<Vector2<f32>>,
+    radius: f64,
+    color: Native<Vector3<f32>>,
+) -> Native<DrawPackage> {
+    draw_commands.0.push(DrawCommand::Circle(center.0, radius as f32, color.0));
+    draw_commands
 }
 
-pub fn vec2(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let x = to_float(args.get(0));
-    let y = to_float(args.get(1));
+pub fn draw_line_list(
+    draw_commands: Native<DrawPackage>,
+    points: Vec<Native<Vector2<f32>>>,
+    color: Native<Vector3<f32>>,
+) -> Option<Native<DrawPackage>> {
+    if points.len() < 2 {
+        return None;
+    }
 
-    Object::create_native_object(Vector2(x, y))
+    let points = points.into_iter().map(|point| point.0).collect();
+    draw_commands.0.push(DrawCommand::Lines(points, color.0));
+    Some(draw_commands)
 }
 
-pub fn vec3(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    let x = to_float(args.get(0));
-    let y = to_float(args.get(1));
-    let z = to_float(args.get(2));
+pub fn vec2(x: f64, y: f64) -> Native<Vector2<f32>> {
+    Native(Vector2(x as f32, y as f32))
+}
 
-    Object::create_native_object(Vector3(x, y, z))
+pub fn vec3(x: f64, y: f64, z: f64) -> Native<Vector3<f32>> {
+    Native(Vector3(x as f32, y as f32, z as f32))
 }
 
-pub fn vec_x(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    if let Some(vec) = to_native::<Vector2<f32>>(args.get(0)) {
-        (vec.0 as f64).into()
-    } else if let Some(vec) = to_native::<Vector3<f32>>(args.get(0)) {
-        (vec.0 as f64).into()
-    } else {
-        Object::Nil
-    }
+pub fn vec_x(vec: AnyVector) -> f64 {
+    vec.0[0] as f64
 }
 
-pub fn vec_y(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    if let Some(vec) = to_native::<Vector2<f32>>(args.get(0)) {
-        (vec.1 as f64).into()
-    } else if let Some(vec) = to_native::<Vector3<f32>>(args.get(0)) {
-        (vec.1 as f64).into()
-    } else {
-        Object::Nil
-    }
+pub fn vec_y(vec: AnyVector) -> f64 {
+    vec.0[1] as f64
 }
 
-pub fn vec_z(_ctx: &mut Context, args: Vec<Object>) -> Object {
-    if let Some(vec) = to_native::<Vector3<f32>>(args.get(0)) {
-        (vec.2 as f64).into()
-    } else {
-        Object::Nil
-    }
+pub fn vec_z(vec: Native<Vector3<f32>>) -> f64 {
+    (vec.0).2 as f64
 }
 
-fn to_native<T: Clone + 'static>(obj: Option<&Object>) -> Option<T> {
-    if let Some(Object::NativeObject(o)) = obj {
-        o.downcast_ref::<T>().map(|o| o.clone())
-    } else {
-        None
+/// Either kind of vector, for the accessors both share. A `vec2` reads with a
+/// z of 0.
+pub struct AnyVector(pub [f32; 3]);
+
+impl FromObject for AnyVector {
+    fn from_object(obj: &Object) -> Option<Self> {
+        if let Some(Native(vec)) = Native::<Vector2<f32>>::from_object(obj) {
+            Some(AnyVector([vec.0, vec.1, 0.0]))
+        } else {
+            Native::<Vector3<f32>>::from_object(obj).map(|Native(vec)| AnyVector(vec.as_array()))
+        }
     }
-}
 
-fn to_float<T: ::std::borrow::Borrow<Object>>(arg: Option<T>) -> f32 {
-    if let Some(Number::Float(f)) = arg.map(|a| core::float_impl(a.borrow())) {
-        f as f32
-    } else {
-        0.0
+    fn expected() -> String {
+        "vec2 or vec3".to_string()
     }
 }
@@ -121,7 +121,7 @@ pub fn create_window(ctx: &mut Context, args: Vec<Object>) -> Object {
 
         let solid_program = glium::Program::from_source(&display, SOLID_VERTEX_SHADER, SOLID_FRAG_SHADER, None);
         if solid_program.is_err() {
-            return Exception::message(format!("Unable to create GL program: {}", solid_program.unwrap_err())).into();
+            return ctx.raise(Exception::new("gfx", format!("Unable to create GL program: {}", solid_program.unwrap_err())));
         }
         let solid_program = solid_program.unwrap();
         let mut line_params = glium::DrawParameters {
@@ -273,6 +273,6 @@ pub fn create_window(ctx: &mut Context, args: Vec<Object>) -> Object {
 
         Object::Nil
     } else {
-        Exception::message("Unable to initialize display").into()
+        ctx.raise(Exception::new("gfx", "Unable to initialize display"))
     }
 }
@@ -4,6 +4,7 @@ use std::collections::HashSet;
 
 use super::*;
 use super::gfx::*;
+use super::core::string_impl;
 
 #[wasm_bindgen]
 #[derive(Debug, Clone)]
@@ -81,9 +82,7 @@ pub fn create_window(ctx: &mut Context, args: Vec<Object>) -> Object {
 
     let window = new_window(dim_x, dim_y);
     let args = vec![(window as i64).into(), Vec::<Object>::new().into(), callback];
-    let callback = NativeFunction {
-        ptr: do_frame,
-    };
+    let callback = NativeFunction::new(do_frame);
 
     ctx.do_yield(callback.into(), args);
 
@@ -98,7 +97,7 @@ const PRESSED: u32 = 0x20000000;
 fn do_frame(ctx: &mut Context, args: Vec<Object>) -> Object {
     let mut args = args.into_iter();
     let window = args.next()
-        .map(|a| integer_impl(&a))
+        .map(|a| core::integer_impl(&a))
         .and_then(|a| match a {
             Number::Integer(num) => Some(num as u32),
             _ => None
@@ -108,7 +107,7 @@ fn do_frame(ctx: &mut Context, args: Vec<Object>) -> Object {
         .next()
         .and_then(|i| match i {
             Object::List(l) => {
-                Some(l.iter().map(|s| string_impl(s)).collect::<Vec<String>>())
+                Some(l.iter().map(|s| string_impl(ctx, s)).collect::<Vec<String>>())
             },
             _ => None,
         }).unwrap_or(Vec::new());
@@ -187,9 +186,7 @@ fn do_frame(ctx: &mut Context, args: Vec<Object>) -> Object {
 
     if !closed {
         let args = vec![(window as i64).into(), callback_inputs, callback];
-        let callback = NativeFunction {
-            ptr: do_frame,
-        };
+        let callback = NativeFunction::new(do_frame);
 
         ctx.do_yield(callback.into(), args);
     }
@@ -25,4 +25,3 @@ pub use self::math::Math;
 pub use self::gfx::Gfx;
 
 use super::lang::*;
-use self::core::*;
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lisp::InterruptHandle;
+
+/// Makes Ctrl-C interrupt the running evaluation instead of killing the
+/// process. When nothing is running it exits as usual.
+pub fn install(handle: InterruptHandle) -> Arc<AtomicBool> {
+    let running = Arc::new(AtomicBool::new(false));
+    platform::install(handle, running.clone());
+    running
+}
+
+fn on_signal(handle: &InterruptHandle, running: &AtomicBool) {
+    if running.load(Ordering::SeqCst) {
+        handle.interrupt();
+    } else {
+        ::std::process::exit(130);
+    }
+}
+
+// The signal handler only writes to a pipe, which is safe to do from one. A
+// thread reading the other end does the rest.
+#[cfg(unix)]
+mod platform {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+    use std::thread;
+
+    use lisp::InterruptHandle;
+    use libc;
+
+    static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle_sigint(_signal: libc::c_int) {
+        let byte = 0u8;
+        unsafe {
+            libc::write(SIGNAL_PIPE.load(Ordering::SeqCst), &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+
+    pub fn install(handle: InterruptHandle, running: Arc<AtomicBool>) {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return;
+        }
+        let (read, write) = (fds[0], fds[1]);
+        SIGNAL_PIPE.store(write, Ordering::SeqCst);
+
+        thread::spawn(move || {
+            let mut byte = 0u8;
+            loop {
+                let count = unsafe { libc::read(read, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+                if count == 1 {
+                    super::on_signal(&handle, &running);
+                } else if count == 0 || ::std::io::Error::last_os_error().kind() != ::std::io::ErrorKind::Interrupted {
+                    break;
+                }
+            }
+        });
+
+        unsafe {
+            libc::signal(libc::SIGINT, handle_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod platform {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    use lisp::InterruptHandle;
+
+    pub fn install(_handle: InterruptHandle, _running: Arc<AtomicBool>) {}
+}
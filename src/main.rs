@@ -1,18 +1,41 @@
 extern crate lisp;
+#[cfg(unix)]
+extern crate libc;
+
+mod ctrl_c;
+
+use std::io::BufRead;
+use std::sync::atomic::Ordering;
 
 use lisp::Context;
 
 fn main() {
     let mut ctx = Context::new();
+    let running = ctrl_c::install(ctx.interrupt_handle());
 
     let mut args = ::std::env::args();
     let _executable = args.next();
     if let Some(path) = args.next() {
-        let _res = ctx.import_module(path);
+        running.store(true, Ordering::SeqCst);
+        if let Err(error) = ctx.try_import_module(path) {
+            eprintln!("Exception:\n{}", error);
+            ::std::process::exit(1);
+        }
     } else {
-        let source = r#"
-(loop(println(eval(readln))))
-"#;
-        let _res = ctx.eval_module(source);
+        // Each line runs on its own, so Ctrl-C stops the line and not the REPL
+        let stdin = ::std::io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            running.store(true, Ordering::SeqCst);
+            let result = ctx.try_eval_module(line);
+            running.store(false, Ordering::SeqCst);
+            match result {
+                Ok(value) => println!("{}", value),
+                Err(error) => println!("{}", error),
+            }
+        }
     }
 }
@@ -0,0 +1,34 @@
+#![cfg(unix)]
+
+extern crate libc;
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn ctrl_c_interrupts_the_running_line_and_keeps_the_repl() {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_mass_lisp"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("starts the repl");
+    let mut stdin = repl.stdin.take().expect("stdin");
+
+    writeln!(stdin, "(loop true 1)").expect("writes");
+    thread::sleep(Duration::from_millis(500));
+    unsafe {
+        libc::kill(repl.id() as libc::pid_t, libc::SIGINT);
+    }
+    thread::sleep(Duration::from_millis(200));
+    writeln!(stdin, "(+ 3 4)").expect("writes");
+    drop(stdin);
+
+    let status = repl.wait().expect("repl exits");
+    let mut output = String::new();
+    repl.stdout.take().expect("stdout").read_to_string(&mut output).expect("reads");
+    assert!(status.success(), "{:?}", status);
+    assert!(output.contains("interrupted: evaluation interrupted"), "{}", output);
+    assert!(output.ends_with("7\n"), "{}", output);
+}